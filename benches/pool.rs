@@ -0,0 +1,50 @@
+//! Compares the schedulers of `Pool` on many tiny jobs submitted from inside of the pool, where
+//! the single channel of `Scheduler::Shared` is contended by every worker.
+//!
+//! Run with `cargo bench`. The difference grows with the number of cores.
+
+#[macro_use]
+extern crate bencher;
+extern crate ergo_sync;
+
+use bencher::Bencher;
+use ergo_sync::*;
+
+/// The jobs run per iteration: `SPLITS` jobs which each submit `JOBS / SPLITS` tiny jobs.
+const JOBS: usize = 10_000;
+const SPLITS: usize = 100;
+
+fn tiny_jobs(bench: &mut Bencher, scheduler: Scheduler) {
+    let pool = Pool::builder().scheduler(scheduler).build();
+    bench.iter(|| {
+        let remaining = Arc::new(AtomicUsize::new(JOBS));
+        let (send_done, recv_done) = ch::bounded(1);
+        for _ in 0..SPLITS {
+            let handle = pool.handle();
+            take!(=remaining, =send_done);
+            pool.submit(move || {
+                for _ in 0..JOBS / SPLITS {
+                    take!(=remaining, =send_done);
+                    handle.submit(move || {
+                        if remaining.fetch_sub(1, AtomicOrdering::SeqCst) == 1 {
+                            ch!(send_done <- ());
+                        }
+                    }).unwrap();
+                }
+            });
+        }
+        ch!(<- recv_done);
+    });
+    pool.finish();
+}
+
+fn shared(bench: &mut Bencher) {
+    tiny_jobs(bench, Scheduler::Shared);
+}
+
+fn work_stealing(bench: &mut Bencher) {
+    tiny_jobs(bench, Scheduler::WorkStealing);
+}
+
+benchmark_group!(benches, shared, work_stealing);
+benchmark_main!(benches);
@@ -0,0 +1,214 @@
+//! Threads which own their state and handle messages one at a time.
+
+use std::error::Error;
+use std::fmt;
+
+use std_prelude::*;
+use ch::{self, DropQueued, OneshotRecvTimeoutError, OneshotSender, SendError, Sender};
+use clock::inherit;
+
+/// State owned by a thread, which is only accessed by handling messages.
+///
+/// This is the common pattern of a thread which owns some state and loops over a
+/// `Receiver<Msg>`, with hooks for when the loop starts and stops. Use [`spawn_actor`] to start
+/// the actor and get its [`Addr`].
+///
+/// [`spawn_actor`]: fn.spawn_actor.html
+/// [`Addr`]: struct.Addr.html
+pub trait Actor: Send + 'static {
+    /// The type of message the actor handles.
+    type Msg: Send + 'static;
+
+    /// Called on the actor's thread before any messages are handled.
+    fn started(&mut self) {}
+
+    /// Handle a single message.
+    ///
+    /// A message which expects a reply should contain a [`OneshotSender`], see [`Addr::ask`].
+    ///
+    /// [`OneshotSender`]: ch/struct.OneshotSender.html
+    /// [`Addr::ask`]: struct.Addr.html#method.ask
+    fn handle(&mut self, msg: Self::Msg);
+
+    /// Called once every `Addr` has been dropped and all of the messages have been handled.
+    ///
+    /// This is not called if `handle` panics.
+    fn stopped(&mut self) {}
+}
+
+/// Spawn a thread running the actor, returning its address.
+///
+/// The actor handles messages in the order they are received. It stops once every [`Addr`] has
+/// been dropped and all of the messages sent before that have been handled.
+///
+/// [`Addr`]: struct.Addr.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// enum Msg {
+///     Add(u64),
+///     Get(ch::OneshotSender<u64>),
+/// }
+///
+/// struct Counter {
+///     count: u64,
+///     done: Sender<u64>,
+/// }
+///
+/// impl Actor for Counter {
+///     type Msg = Msg;
+///
+///     fn handle(&mut self, msg: Msg) {
+///         match msg {
+///             Msg::Add(n) => self.count += n,
+///             Msg::Get(reply) => ch!(reply <- self.count),
+///         }
+///     }
+///
+///     fn stopped(&mut self) {
+///         self.done.send(self.count).unwrap();
+///     }
+/// }
+///
+/// # fn main() {
+/// let (send_done, recv_done) = ch::bounded(1);
+/// let addr = spawn_actor(Counter { count: 0, done: send_done });
+///
+/// for n in 1..5 {
+///     addr.tell(Msg::Add(n)).unwrap();
+/// }
+/// let count = addr.ask(Msg::Get, Duration::from_secs(1)).unwrap();
+/// assert_eq!(10, count);
+///
+/// // the actor stops once all of its addresses are dropped
+/// drop(addr);
+/// assert_eq!(10, ch!(<- recv_done));
+/// # }
+/// ```
+pub fn spawn_actor<A: Actor>(mut actor: A) -> Addr<A::Msg> {
+    let (send, recv) = ch::unbounded();
+    spawn(inherit(move || {
+        // Drop the queued messages when the thread exits, even if the actor panicked.
+        let recv = DropQueued(recv);
+        actor.started();
+        for msg in recv.0.iter() {
+            actor.handle(msg);
+        }
+        actor.stopped();
+    }));
+    Addr { send }
+}
+
+/// The address of an [`Actor`], used to send it messages.
+///
+/// Cloning the address is cheap. The actor stops once every clone has been dropped.
+///
+/// [`Actor`]: trait.Actor.html
+#[derive(Debug)]
+pub struct Addr<M> {
+    send: Sender<M>,
+}
+
+impl<M> Clone for Addr<M> {
+    fn clone(&self) -> Addr<M> {
+        Addr {
+            send: self.send.clone(),
+        }
+    }
+}
+
+impl<M> Addr<M> {
+    /// Send a message without waiting for it to be handled.
+    ///
+    /// Returns the message in the `Err` if the actor has stopped (i.e. it panicked). This never
+    /// blocks.
+    pub fn tell(&self, msg: M) -> Result<(), SendError<M>> {
+        self.send.send(msg)
+    }
+
+    /// Send a message containing a reply channel, and wait up to `timeout` for the reply.
+    ///
+    /// `msg` creates the message from the sending half of a [`oneshot`] channel, i.e. an enum
+    /// variant such as `Msg::Get`. The actor replies by sending on it.
+    ///
+    /// If the actor panics the messages waiting for it are dropped, so asking fails with
+    /// `AskError::Stopped` or `AskError::Dropped` instead of waiting for the timeout.
+    ///
+    /// [`oneshot`]: ch/fn.oneshot.html
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// struct Fragile;
+    ///
+    /// impl Actor for Fragile {
+    ///     type Msg = Option<ch::OneshotSender<()>>;
+    ///
+    ///     fn handle(&mut self, msg: Self::Msg) {
+    ///         match msg {
+    ///             Some(reply) => ch!(reply <- ()),
+    ///             None => panic!("fragile"),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// let addr = spawn_actor(Fragile);
+    /// assert_eq!(Ok(()), addr.ask(Some, Duration::from_secs(5)));
+    ///
+    /// addr.tell(None).unwrap();
+    /// let err = addr.ask(Some, Duration::from_secs(60)).unwrap_err();
+    /// assert!(err == AskError::Stopped || err == AskError::Dropped);
+    /// # }
+    /// ```
+    pub fn ask<R, F>(&self, msg: F, timeout: Duration) -> Result<R, AskError>
+    where
+        F: FnOnce(OneshotSender<R>) -> M,
+    {
+        let (send_reply, recv_reply) = ch::oneshot();
+        if self.send.send(msg(send_reply)).is_err() {
+            return Err(AskError::Stopped);
+        }
+        recv_reply.recv_timeout(timeout).map_err(|err| match err {
+            OneshotRecvTimeoutError::Timeout => AskError::Timeout,
+            OneshotRecvTimeoutError::Dropped => AskError::Dropped,
+            // The reply channel was created above, so it can only be received once.
+            OneshotRecvTimeoutError::Disconnected => unreachable!(),
+        })
+    }
+
+    /// Returns `true` if the actor has stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.send.is_disconnected()
+    }
+}
+
+/// The error returned by [`Addr::ask`].
+///
+/// [`Addr::ask`]: struct.Addr.html#method.ask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AskError {
+    /// The actor has stopped, so the message was not sent.
+    Stopped,
+    /// The actor did not reply in time.
+    Timeout,
+    /// The actor dropped the reply channel without replying.
+    Dropped,
+}
+
+impl fmt::Display for AskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            AskError::Stopped => "asking a stopped actor",
+            AskError::Timeout => "timed out waiting on reply",
+            AskError::Dropped => "actor dropped the request without replying",
+        })
+    }
+}
+
+impl Error for AskError {}
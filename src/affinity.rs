@@ -0,0 +1,112 @@
+//! Pinning threads to CPU cores.
+//!
+//! Only Linux is supported. On other platforms the pool options which use this do not exist.
+
+use std::io;
+use std::mem;
+
+use libc;
+use std_prelude::*;
+
+/// Restrict the current thread to run on the given cores (numbered from 0).
+///
+/// This is `sched_setaffinity` for the current thread. Pinning CPU bound threads to distinct
+/// cores can reduce cache thrashing, see [`PoolBuilder::pin_cores`] to do this for a pool.
+///
+/// [`PoolBuilder::pin_cores`]: struct.PoolBuilder.html#method.pin_cores
+///
+/// # Errors
+/// Returns an error if `cores` is empty or none of the cores are available to the process.
+///
+/// # Examples
+/// ```rust
+/// # extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let cores = allowed_cores().unwrap();
+/// spawn(move || {
+///     set_affinity(&cores[..1]).unwrap();
+///     assert_eq!(cores[..1].to_vec(), allowed_cores().unwrap());
+/// }).finish();
+/// # }
+/// ```
+pub fn set_affinity(cores: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &core in cores {
+            if core >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("core {} is out of range", core),
+                ));
+            }
+            libc::CPU_SET(core, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The cores the current thread is allowed to run on.
+///
+/// This is `sched_getaffinity` for the current thread, which is usually every core unless the
+/// process was started with i.e. `taskset`.
+pub fn allowed_cores() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|&core| libc::CPU_ISSET(core, &set))
+            .collect())
+    }
+}
+
+/// The cores the workers of a pool run on.
+#[derive(Debug)]
+pub(crate) struct Affinity {
+    cores: Vec<usize>,
+    pin: bool,
+    /// The core to pin the next worker to.
+    next: AtomicUsize,
+}
+
+impl Affinity {
+    /// Restrict the workers to `cores` (or the allowed cores if `None`), pinning each worker to
+    /// a single core if `pin` is `true`.
+    pub(crate) fn new(cores: Option<Vec<usize>>, pin: bool) -> Affinity {
+        let cores = match cores {
+            Some(cores) => cores,
+            None => allowed_cores().unwrap_or_default(),
+        };
+        Affinity {
+            cores,
+            pin,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Apply the affinity to the current (worker) thread.
+    ///
+    /// The cores were checked when the pool was built, but the cores allowed for the process can
+    /// change while it runs. Killing the worker would silently shrink the pool, so a worker which
+    /// cannot be pinned is only restricted to all of the cores instead, and a worker which cannot
+    /// be restricted at all runs on any core.
+    pub(crate) fn apply(&self) {
+        if self.cores.is_empty() {
+            return;
+        }
+        if self.pin {
+            let i = self.next.fetch_add(1, AtomicOrdering::SeqCst) % self.cores.len();
+            if set_affinity(&self.cores[i..i + 1]).is_ok() {
+                return;
+            }
+        }
+        let _ = set_affinity(&self.cores);
+    }
+}
@@ -0,0 +1,282 @@
+//! Assertions about the behavior of channels, for use in tests.
+//!
+//! The macros call the functions here, which are public so that the macros can call them but are
+//! not part of the API.
+
+use std::fmt::{self, Debug};
+
+use std_prelude::*;
+use super::{Receiver, RecvTimeoutError, SendTimeoutError, Sender};
+
+/// How long the assertions wait for a value (or for the channel to close) by default.
+pub const WITHIN: Duration = Duration::from_secs(5);
+
+/// How long `assert_no_recv!` waits by default.
+pub const NO_RECV_FOR: Duration = Duration::from_millis(100);
+
+/// Assert that `expected` is received from `recv` within the given duration.
+///
+/// The duration defaults to 5 seconds, so that a failing test does not hang.
+///
+/// # Panics
+/// Panics if a different value is received, the channel is disconnected or nothing is received
+/// in time. The message includes both values and the state of the channel, i.e.:
+///
+/// ```text
+/// assertion failed: received an unexpected value for `recv`.
+///  received: `3`
+///  expected: `4`
+///   channel: `recv` (2 queued, connected)
+/// ```
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(2);
+/// spawn(move || {
+///     sleep_ms(10);
+///     ch!(send <- 4);
+/// });
+/// assert_recv!(recv, 4);
+/// assert_closed!(recv, within = Duration::from_secs(1));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_recv {
+    ($recv:expr, $expected:expr $(,)*) => {
+        $crate::ch::asserts::recv(
+            &$recv,
+            $expected,
+            $crate::ch::asserts::WITHIN,
+            stringify!($recv),
+        )
+    };
+    ($recv:expr, $expected:expr, within = $within:expr $(,)*) => {
+        $crate::ch::asserts::recv(&$recv, $expected, $within, stringify!($recv))
+    };
+}
+
+/// Assert that nothing is received from `recv` for the given duration.
+///
+/// The duration defaults to 100 milliseconds. A disconnected channel passes, since nothing can be
+/// received from it.
+///
+/// # Panics
+/// Panics with the value and the state of the channel if a value is received.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(1);
+/// assert_no_recv!(recv);
+///
+/// ch!(send <- 7);
+/// assert_recv!(recv, 7);
+///
+/// // nothing can be received once the senders are dropped
+/// drop(send);
+/// assert_no_recv!(recv, for = Duration::from_millis(10));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_no_recv {
+    ($recv:expr $(,)*) => {
+        $crate::ch::asserts::no_recv(&$recv, $crate::ch::asserts::NO_RECV_FOR, stringify!($recv))
+    };
+    ($recv:expr, for = $for:expr $(,)*) => {
+        $crate::ch::asserts::no_recv(&$recv, $for, stringify!($recv))
+    };
+}
+
+/// Assert that every sender of `recv` is dropped within the given duration, without any more
+/// values being received.
+///
+/// This is `ch!(! <- recv)` which cannot hang. The duration defaults to 5 seconds.
+///
+/// # Panics
+/// Panics if a value is received (with the same message as `ch!(! <- recv)`) or the senders are
+/// not dropped in time.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::unbounded();
+/// spawn(move || {
+///     for i in 0..3 {
+///         ch!(send <- i);
+///     }
+/// });
+/// for i in 0..3 {
+///     assert_recv!(recv, i);
+/// }
+/// assert_closed!(recv);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_closed {
+    ($recv:expr $(,)*) => {
+        $crate::ch::asserts::closed(&$recv, $crate::ch::asserts::WITHIN, stringify!($recv))
+    };
+    ($recv:expr, within = $within:expr $(,)*) => {
+        $crate::ch::asserts::closed(&$recv, $within, stringify!($recv))
+    };
+}
+
+/// Assert that `value` can be sent on `send` within the given duration.
+///
+/// This is `ch!(send <- value)` which cannot hang on a full channel. The duration defaults to 5
+/// seconds.
+///
+/// # Panics
+/// Panics with the value and the state of the channel if the receivers are disconnected or the
+/// channel stays full.
+///
+/// # Examples
+/// ```rust,should_panic
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(1);
+/// assert_sent!(send, 1);
+/// assert_recv!(recv, 1);
+/// assert_sent!(send, 2);
+///
+/// // panics, since the channel is full
+/// assert_sent!(send, 3, within = Duration::from_millis(10));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_sent {
+    ($send:expr, $value:expr $(,)*) => {
+        $crate::ch::asserts::sent(&$send, $value, $crate::ch::asserts::WITHIN, stringify!($send))
+    };
+    ($send:expr, $value:expr, within = $within:expr $(,)*) => {
+        $crate::ch::asserts::sent(&$send, $value, $within, stringify!($send))
+    };
+}
+
+#[track_caller]
+pub fn recv<T: Debug + PartialEq>(recv: &Receiver<T>, expected: T, within: Duration, name: &str) {
+    match recv.recv_timeout(within) {
+        Ok(ref v) if *v == expected => {}
+        Ok(v) => panic!(
+            "assertion failed: received an unexpected value for `recv`.\n \
+             received: `{:?}`\n \
+             expected: `{:?}`\n  \
+             channel: {}",
+            v,
+            expected,
+            Context::recv(name, recv)
+        ),
+        Err(err) => panic!(
+            "assertion failed: expected `{:?}`, but {} for `recv`.\n  \
+             channel: {}",
+            expected,
+            err,
+            Context::recv(name, recv).waited(within)
+        ),
+    }
+}
+
+#[track_caller]
+pub fn no_recv<T: Debug>(recv: &Receiver<T>, dur: Duration, name: &str) {
+    if let Ok(v) = recv.recv_timeout(dur) {
+        panic!(
+            "assertion failed: expected no value, but got `{:?}` for `recv`.\n  \
+             channel: {}",
+            v,
+            Context::recv(name, recv).waited(dur)
+        );
+    }
+}
+
+#[track_caller]
+pub fn closed<T: Debug>(recv: &Receiver<T>, within: Duration, name: &str) {
+    match recv.recv_timeout(within) {
+        Err(RecvTimeoutError::Disconnected) => {}
+        Ok(v) => panic!(
+            "assertion failed: Got `{:?}` when expecting senders to be closed.\n  \
+             channel: {}",
+            v,
+            Context::recv(name, recv)
+        ),
+        Err(err @ RecvTimeoutError::Timeout) => panic!(
+            "assertion failed: expected the senders to be closed, but {} for `recv`.\n  \
+             channel: {}",
+            err,
+            Context::recv(name, recv).waited(within)
+        ),
+    }
+}
+
+#[track_caller]
+pub fn sent<T: Debug>(send: &Sender<T>, value: T, within: Duration, name: &str) {
+    if let Err(err) = send.send_timeout(value, within) {
+        let message = err.to_string();
+        let context = Context {
+            name,
+            len: send.len(),
+            disconnected: send.is_disconnected(),
+            waited: match err {
+                SendTimeoutError::Timeout(_) => Some(within),
+                SendTimeoutError::Disconnected(_) => None,
+            },
+        };
+        panic!(
+            "assertion failed: could not send `{:?}`, {} for `send`.\n  \
+             channel: {}",
+            err.into_inner(),
+            message,
+            context
+        );
+    }
+}
+
+/// The state of a channel, i.e. `` `recv` (2 queued, connected, waited 5s) ``.
+struct Context<'a> {
+    name: &'a str,
+    len: usize,
+    disconnected: bool,
+    waited: Option<Duration>,
+}
+
+impl<'a> Context<'a> {
+    fn recv<T>(name: &'a str, recv: &Receiver<T>) -> Context<'a> {
+        Context {
+            name,
+            len: recv.len(),
+            disconnected: recv.is_disconnected(),
+            waited: None,
+        }
+    }
+
+    fn waited(mut self, dur: Duration) -> Context<'a> {
+        self.waited = Some(dur);
+        self
+    }
+}
+
+impl<'a> fmt::Display for Context<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` ({} queued, ", self.name, self.len)?;
+        f.write_str(if self.disconnected {
+            "disconnected"
+        } else {
+            "connected"
+        })?;
+        if let Some(waited) = self.waited {
+            write!(f, ", waited {:?}", waited)?;
+        }
+        f.write_str(")")
+    }
+}
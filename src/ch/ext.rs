@@ -0,0 +1,183 @@
+//! Extension traits for the channel types.
+
+use std_prelude::*;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use clock::Clock;
+use future::{RecvStream, SendFuture};
+
+/// Adapters which consume a `Receiver`.
+///
+/// Each adapter spawns an internal thread which forwards values from the original channel into the
+/// returned one. The thread exits when the original channel is disconnected (after forwarding any
+/// pending value) or when the returned channel is dropped.
+pub trait ReceiverExt<T> {
+    /// Only emit a value after the channel has been quiet for `dur`.
+    ///
+    /// Every value received resets the timer, and only the most recent value is emitted. This is
+    /// useful for streams of events which come in bursts (i.e. file watching) where only the
+    /// final state is interesting.
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    /// use ergo_sync::ch::ReceiverExt;
+    ///
+    /// # fn main() {
+    /// let (send, recv) = ch::unbounded();
+    /// let recv = recv.debounce(Duration::from_millis(50));
+    ///
+    /// for i in 0..10 {
+    ///     ch!(send <- i);
+    /// }
+    /// assert_eq!(9, ch!(<- recv));
+    ///
+    /// drop(send);
+    /// ch!(! <- recv);
+    /// # }
+    /// ```
+    fn debounce(self, dur: Duration) -> Receiver<T>;
+
+    /// Emit at most one value per `dur`.
+    ///
+    /// The first value is emitted immediately. Values received before `dur` has elapsed are
+    /// dropped, except for the most recent one which is emitted once the interval is over.
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    /// use ergo_sync::ch::ReceiverExt;
+    ///
+    /// # fn main() {
+    /// let (send, recv) = ch::unbounded();
+    /// let recv = recv.throttle(Duration::from_millis(50));
+    ///
+    /// for i in 0..10 {
+    ///     ch!(send <- i);
+    /// }
+    /// drop(send);
+    ///
+    /// let values: Vec<_> = recv.iter().collect();
+    /// assert_eq!(vec![0, 9], values);
+    /// # }
+    /// ```
+    fn throttle(self, dur: Duration) -> Receiver<T>;
+
+    /// Convert the `Receiver` into a [`RecvStream`], for receiving values inside of a future.
+    ///
+    /// See the [`future` module] for more information.
+    ///
+    /// [`RecvStream`]: ../future/struct.RecvStream.html
+    /// [`future` module]: ../future/index.html
+    fn into_stream(self) -> RecvStream<T>;
+}
+
+/// Adapters for `Sender`.
+pub trait SenderExt<T> {
+    /// Get a future which completes once `value` has been sent, or which returns the value if all
+    /// receivers have been dropped.
+    ///
+    /// See the [`future` module] for more information.
+    ///
+    /// [`future` module]: ../future/index.html
+    fn send_async(&self, value: T) -> SendFuture<T>;
+}
+
+impl<T: Send + 'static> SenderExt<T> for Sender<T> {
+    fn send_async(&self, value: T) -> SendFuture<T> {
+        SendFuture::new(self.clone(), value)
+    }
+}
+
+impl<T: Send + 'static> ReceiverExt<T> for Receiver<T> {
+    fn debounce(self, dur: Duration) -> Receiver<T> {
+        let (send, recv) = unbounded();
+        let clock = Clock::current();
+        spawn(move || {
+            let mut pending = None;
+            loop {
+                let received = match pending {
+                    Some(_) => clock.recv_timeout(&self, dur),
+                    None => self.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(v) => pending = Some(v),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Some(v) = pending.take() {
+                            if send.send(v).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        if let Some(v) = pending.take() {
+                            let _ = send.send(v);
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+        recv
+    }
+
+    fn throttle(self, dur: Duration) -> Receiver<T> {
+        let (send, recv) = unbounded();
+        let clock = Clock::current();
+        spawn(move || {
+            let mut pending = None;
+            // The earliest time the next value may be emitted.
+            let mut next = clock.now();
+            loop {
+                let received = match pending {
+                    Some(_) => {
+                        let now = clock.now();
+                        if now >= next {
+                            Err(RecvTimeoutError::Timeout)
+                        } else {
+                            clock.recv_timeout(&self, next - now)
+                        }
+                    }
+                    None => self.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(v) => {
+                        let now = clock.now();
+                        if pending.is_none() && now >= next {
+                            if send.send(v).is_err() {
+                                return;
+                            }
+                            next = now + dur;
+                        } else {
+                            pending = Some(v);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Some(v) = pending.take() {
+                            if send.send(v).is_err() {
+                                return;
+                            }
+                            next = clock.now() + dur;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        if let Some(v) = pending.take() {
+                            let now = clock.now();
+                            if now < next {
+                                clock.sleep(next - now);
+                            }
+                            let _ = send.send(v);
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+        recv
+    }
+
+    fn into_stream(self) -> RecvStream<T> {
+        RecvStream::new(self)
+    }
+}
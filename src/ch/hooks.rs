@@ -0,0 +1,139 @@
+//! The operations performed by the `ch!` macro.
+//!
+//! These are public so that the macro can call them, but are not part of the API. They exist so
+//! that the operations can be scheduled by the `sim` module when it is enabled.
+
+use std::fmt;
+use std::sync::mpsc;
+
+#[cfg(feature = "sim")]
+use sim;
+use super::{Receiver, RecvError, SendError, Sender};
+#[cfg(feature = "sim")]
+use super::{TryRecvError, TrySendError};
+
+/// A channel which can be sent on with `ch!(send <- value)`.
+pub trait ChSend {
+    type Item;
+    type Error: fmt::Display;
+
+    fn ch_send(self, value: Self::Item) -> Result<(), Self::Error>;
+}
+
+/// A channel which can be received from with `ch!(<- recv)`.
+pub trait ChRecv {
+    type Item;
+    type Error: fmt::Display;
+
+    fn ch_recv(self) -> Result<Self::Item, Self::Error>;
+}
+
+/// Let another thread run before a non-blocking operation, if the thread is being simulated.
+#[inline]
+pub fn yield_now() {
+    #[cfg(feature = "sim")]
+    {
+        if let Some(task) = sim::current() {
+            task.yield_now();
+        }
+    }
+}
+
+impl<T> ChSend for &Sender<T> {
+    type Item = T;
+    type Error = SendError<T>;
+
+    fn ch_send(self, value: T) -> Result<(), SendError<T>> {
+        #[cfg(feature = "sim")]
+        {
+            if let Some(task) = sim::current() {
+                let mut value = Some(value);
+                return task.block_on("send", || {
+                    match self.try_send(value.take().expect("value is put back when full")) {
+                        Ok(()) => Some(Ok(())),
+                        Err(TrySendError::Full(v)) => {
+                            value = Some(v);
+                            None
+                        }
+                        Err(TrySendError::Disconnected(v)) => Some(Err(SendError(v))),
+                    }
+                });
+            }
+        }
+        self.send(value)
+    }
+}
+
+impl<T> ChRecv for &Receiver<T> {
+    type Item = T;
+    type Error = RecvError;
+
+    fn ch_recv(self) -> Result<T, RecvError> {
+        #[cfg(feature = "sim")]
+        {
+            if let Some(task) = sim::current() {
+                return task.block_on("recv", || match self.try_recv() {
+                    Ok(v) => Some(Ok(v)),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+                });
+            }
+        }
+        self.recv()
+    }
+}
+
+impl<T> ChSend for &mpsc::Sender<T> {
+    type Item = T;
+    type Error = mpsc::SendError<T>;
+
+    fn ch_send(self, value: T) -> Result<(), mpsc::SendError<T>> {
+        // Unbounded, so this never blocks.
+        yield_now();
+        self.send(value)
+    }
+}
+
+impl<T> ChSend for &mpsc::SyncSender<T> {
+    type Item = T;
+    type Error = mpsc::SendError<T>;
+
+    fn ch_send(self, value: T) -> Result<(), mpsc::SendError<T>> {
+        #[cfg(feature = "sim")]
+        {
+            if let Some(task) = sim::current() {
+                let mut value = Some(value);
+                return task.block_on("send", || {
+                    match self.try_send(value.take().expect("value is put back when full")) {
+                        Ok(()) => Some(Ok(())),
+                        Err(mpsc::TrySendError::Full(v)) => {
+                            value = Some(v);
+                            None
+                        }
+                        Err(mpsc::TrySendError::Disconnected(v)) => Some(Err(mpsc::SendError(v))),
+                    }
+                });
+            }
+        }
+        self.send(value)
+    }
+}
+
+impl<T> ChRecv for &mpsc::Receiver<T> {
+    type Item = T;
+    type Error = mpsc::RecvError;
+
+    fn ch_recv(self) -> Result<T, mpsc::RecvError> {
+        #[cfg(feature = "sim")]
+        {
+            if let Some(task) = sim::current() {
+                return task.block_on("recv", || match self.try_recv() {
+                    Ok(v) => Some(Ok(v)),
+                    Err(mpsc::TryRecvError::Empty) => None,
+                    Err(mpsc::TryRecvError::Disconnected) => Some(Err(mpsc::RecvError)),
+                });
+            }
+        }
+        self.recv()
+    }
+}
@@ -0,0 +1,746 @@
+//! Module for working with channels. Rexport of [`crossbeam_channel`]
+//!
+//!
+//! # Examples
+//!
+//! > Several of these examples are copies of the [`chan`] and [`crossbeam_channel`] crates.
+//!
+//! [`chan`]: https://github.com/BurntSushi/chan
+//! [`crossbeam_channel`]: ../../crossbeam_channel/index.html
+//!
+//! ## Example: unbounded (async) channel
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! let (tx, rx) = ch::unbounded();
+//!
+//! // Can send an arbitrarily large number of messages.
+//! for i in 0..1000 {
+//!     ch!(tx <- i);
+//! }
+//! # }
+//! ```
+//!
+//! ## Example: bounded (sync) channel
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! // Create a channel that can hold at most 5 messages at a time.
+//! let (tx, rx) = ch::bounded(5);
+//!
+//! // Can send only 5 messages.
+//! for i in 0..5 {
+//!     ch!(tx <- i);
+//! }
+//!
+//! // An attempt to send one more message will fail.
+//! assert!(tx.try_send(5).is_err());
+//! # }
+//! ```
+//!
+//! ## Example: rendevous channel
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! let (send, recv) = ch::bounded(0);
+//! spawn(move || ch!(send <- 5));
+//! assert_eq!(ch!(<- recv), 5); // blocks until the previous send occurs
+//! # }
+//! ```
+//!
+//! ## Example: the sentinel channel idiom
+//!
+//! When writing concurrent programs with `ergo`, you will often find that you need
+//! to somehow "wait" until some operation is done. For example, let's say you want
+//! to run a function in a separate thread, but wait until it completes. Here's
+//! one way to do it:
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! fn do_work(done: ch::Sender<()>) {
+//!     // do something
+//!
+//!     // signal that we're done.
+//!     ch!(done <- ());
+//! }
+//!
+//! fn main() {
+//!     let (sdone, rdone) = ch::bounded(0);
+//!     spawn(move || do_work(sdone));
+//!     // block until work is done, and then quit the program.
+//!     ch!(<- rdone);
+//! }
+//! ```
+//!
+//! In effect, we've created a new channel that sends unit values. When we're
+//! done doing work, we send a unit value and `main` waits for it to be delivered.
+//!
+//! Another way of achieving the same thing is to simply close the channel. Once
+//! the channel is closed, any previously blocked receive operations become
+//! immediately unblocked. What's even cooler is that channels are closed
+//! automatically when all senders are dropped. So the new program looks something
+//! like this:
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! fn do_work(_done: ch::Sender<()>) {
+//!     // do something
+//! }
+//!
+//! fn main() {
+//!     let (sdone, rdone) = ch::bounded(0);
+//!     spawn(move || do_work(sdone));
+//!     // Block until the channel is closed.
+//!     //
+//!     // Note: this _expects_ the error that
+//!     // all senders have been dropped and will
+//!     // panic if a value is sent instead.
+//!     ch!(! <- rdone);
+//! }
+//! ```
+//!
+//! We no longer need to explicitly do anything with the `_done` channel. We give
+//! `do_work` ownership of the channel, but as soon as the function stops
+//! executing, `_done` is dropped, the channel is closed and `rdone.recv()`
+//! unblocks returning an error, which we expect with `ch!(! <- rdone)`.
+//!
+//! ## Example: non-blocking sends/receives
+//!
+//! ```
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! let (send, recv) = ch::bounded(1);
+//! let data = "send data".to_string();
+//! match ch!(send <-? data) {
+//!     Some(data) => {
+//!         println!("didn't send data, but got it back: {}", data);
+//!         unreachable!(); // in this case we don't expect it
+//!     }
+//!     None => println!("message sent successfully"),
+//! }
+//!
+//! // attempting to send additional data fails
+//! let data = "more data".to_string();
+//! assert_eq!(Some(data.clone()), ch!(send <-? data));
+//!
+//! match ch!(<-? recv) {
+//!     Some(data) => println!("received data: {}", data),
+//!     None => {
+//!         println!("didn't receive any data yet");
+//!         unreachable!(); // in this case we don't expect it
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! ## Example: using `select_loop`
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! let (tx1, rx1) = ch::unbounded();
+//! let (tx2, rx2) = ch::unbounded();
+//!
+//! spawn(move || ch!(tx1 <- "foo"));
+//! spawn(move || ch!(tx2 <- "bar"));
+//!
+//! select_loop! {
+//!     recv(rx1, msg) => {
+//!         println!("Received a message from the first channel: {}", msg);
+//!     }
+//!     recv(rx2, msg) => {
+//!         println!("Received a message from the second channel: {}", msg);
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! ## Example: debouncing a stream of events
+//!
+//! The [`ReceiverExt`] trait provides adapters for `Receiver`, such as `debounce` and
+//! `throttle`, which each return a new `Receiver`.
+//!
+//! [`ReceiverExt`]: trait.ReceiverExt.html
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//! use ergo_sync::ch::ReceiverExt;
+//!
+//! # fn main() {
+//! let (send, recv) = ch::unbounded();
+//! let events = recv.debounce(Duration::from_millis(20));
+//!
+//! ch!(send <- "modified");
+//! ch!(send <- "modified");
+//! ch!(send <- "closed");
+//!
+//! assert_eq!("closed", ch!(<- events));
+//! # }
+//! ```
+//!
+//! ## Example: timeouts and heartbeats
+//!
+//! [`after`] and [`tick`] create channels which receive the current `Instant` after a delay or at
+//! an interval. Since they are ordinary channels they can be used as arms of `select_loop!`.
+//!
+//! [`after`]: fn.after.html
+//! [`tick`]: fn.tick.html
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! let (send, recv) = ch::unbounded();
+//! spawn(move || {
+//!     sleep_ms(30);
+//!     ch!(send <- "finished");
+//! });
+//!
+//! let heartbeat = ch::tick(Duration::from_millis(10));
+//! let timeout = ch::after(Duration::from_secs(5));
+//! 'outer: loop {
+//!     select_loop! {
+//!         recv(heartbeat, _) => println!("still waiting..."),
+//!         recv(recv, msg) => {
+//!             println!("got: {}", msg);
+//!             break 'outer;
+//!         }
+//!         recv(timeout, _) => panic!("timed out"),
+//!     }
+//! }
+//! # }
+//! ```
+
+pub use crossbeam_channel::{bounded, unbounded, IntoIter, Iter, Receiver, RecvError,
+                            RecvTimeoutError, Select, SelectRecvError, SelectSendError, SendError,
+                            SendTimeoutError, Sender, TryIter, TryRecvError, TrySendError};
+
+pub use self::ext::{ReceiverExt, SenderExt};
+pub use self::oneshot::{oneshot, OneshotReceiver, OneshotRecvError, OneshotRecvTimeoutError,
+                        OneshotSender};
+pub use self::rpc::{rpc, Client, Responder, RpcError, Server, ServerIntoIter};
+#[cfg(unix)]
+pub use self::signal::{drain_on_interrupt, signals, Drain, Signal, SIGHUP, SIGINT, SIGQUIT,
+                       SIGTERM, SIGUSR1, SIGUSR2};
+pub use self::timer::{after, tick};
+
+#[doc(hidden)]
+#[macro_use]
+pub mod asserts;
+mod ext;
+#[doc(hidden)]
+pub mod hooks;
+mod oneshot;
+mod rpc;
+#[cfg(unix)]
+mod signal;
+pub(crate) mod timer;
+
+/// Drops the values still queued in a channel once it is dropped.
+///
+/// A channel keeps queued values alive until every sender has been dropped as well. Dropping
+/// them as soon as nothing will receive them lets i.e. the reply channels inside of them report
+/// that no reply is coming.
+pub(crate) struct DropQueued<T>(pub(crate) Receiver<T>);
+
+impl<T> Drop for DropQueued<T> {
+    fn drop(&mut self) {
+        for _ in self.0.try_iter() {}
+    }
+}
+
+/// Use with channels with ergonomic syntax and panic with helpful error messages when
+/// sending/receiving on a channel is invalid.
+///
+///   - `ch!(send <- 42)` for sending a value.
+///   - `let v = ch!(<- recv)` for receiving a value.
+///   - `ch!(! <- recv)` to wait for channels to close.
+///   - `<-?` for async operation support.
+///
+/// **Blocking syntax:**
+///
+/// - `ch!(send <- value)`: blocks until a value is sent, panics if all receivers are dropped.
+/// - `ch!(<- recv)`: blocks until a value is received, panics if all senders are dropped.
+/// - `ch!(! <- recv)`: blocks until all senders are dropped, panics if a value is received. Used
+///   for signaling.
+///
+/// > This syntax works with both `crossbeam-channel` channels (which are exported by this crate) as
+/// > well as `std::mspc` channels.
+///
+/// > Note that these operations can deadlock if a channel is leaked.
+///
+/// **Non-Blocking syntax:**
+///
+/// - `ch!(send <-? value)`: returns `None` if the value was sent, `Some(value)` if the value
+///   was not sent. Panics if all receivers are dropped.
+/// - `ch!(<-? recv)`: returns `None` if no value is received, `Some(value)` if a value is
+///   received. Panics if all senders are dropped.
+/// - `ch!(! <-? recv)`: returns `true` if there are still senders and `false` if the seners have
+///   been dropped. Panics if a value is received. Use with `while ch!(! <-? recv) { /* ... */ }`
+///
+/// > Non-Blocking syntax does _not_ work with `std::mspc` channels.
+///
+/// # Examples
+///
+/// ## Example: Using `ergo::chan` channels
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// # fn main() {
+/// let (send, recv) = ch::bounded(3);
+/// ch!(send <- 4);
+/// ch!(send <- 7);
+/// ch!(send <- 42);
+/// assert_eq!(4, ch!(<- recv));
+/// assert_eq!(7, ch!(<- recv));
+/// let v = ch!(<- recv);
+/// assert_eq!(42, v);
+///
+/// drop(send);
+/// // ch!(<- recv); // panics
+/// ch!(! <- recv);  // succeeds
+/// # }
+/// ```
+///
+/// ## Example: Using `std::mspc` channels
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use std::sync::mpsc::sync_channel;
+///
+/// # fn main() {
+/// let (send, recv) = sync_channel(3);
+/// ch!(send <- 4);
+/// ch!(send <- 7);
+/// ch!(send <- 42);
+/// assert_eq!(4, ch!(<- recv));
+/// assert_eq!(7, ch!(<- recv));
+/// let v = ch!(<- recv);
+/// assert_eq!(42, v);
+///
+/// drop(send);
+/// // ch!(<- recv); // panics
+/// ch!(! <- recv);  // succeeds
+/// # }
+/// ```
+///
+/// ## Example: using non-blocking syntax
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// # fn main() {
+/// let (send, recv) = ch::bounded(3);
+/// assert_eq!(None, ch!(<-? recv)); // no values sent yet
+///
+/// assert!(ch!(send <-? 4).is_none());
+/// assert_eq!(Some(4), ch!(<-? recv));
+/// assert_eq!(None, ch!(<-? recv));
+///
+/// assert!(ch!(send <-? 7).is_none());
+/// assert!(ch!(send <-? 42).is_none());
+/// assert!(ch!(send <-? 1).is_none());
+/// // further attempts return the value
+/// assert_eq!(Some(100), ch!(send <-? 100));
+///
+/// assert_eq!(Some(7), ch!(<-? recv));
+///
+/// assert_eq!(Some(42), ch!(<-? recv));
+/// assert_eq!(Some(1), ch!(<-? recv));
+/// assert_eq!(None, ch!(<-? recv));
+/// assert!(ch!(! <-? recv)); // senders still exist
+///
+/// drop(send);
+/// // ch!(<-? recv); // panics
+/// ch!(! <-? recv);  // succeeds
+/// # }
+/// ```
+#[macro_export]
+macro_rules! ch {
+    [$send:ident <-? $value:expr] => {{
+        $crate::ch::hooks::yield_now();
+        match $send.try_send($value) {
+            Ok(()) => None,
+            Err($crate::ch::TrySendError::Full(v)) => Some(v),
+            Err($crate::ch::TrySendError::Disconnected(_)) => {
+                panic!("Attempted to send a value but receivers are disconnected");
+            }
+        }
+    }};
+
+    [$send:ident <- $value:expr] => {{
+        use $crate::ch::hooks::ChSend;
+        match $send.ch_send($value) {
+            Ok(_) => {},
+            Err(err) => panic!("{} for `send`.", err),
+        }
+    }};
+
+    [<-? $recv:ident] => {{
+        $crate::ch::hooks::yield_now();
+        match $recv.try_recv() {
+            Ok(v) => Some(v),
+            Err($crate::ch::TryRecvError::Empty) => None,
+            Err($crate::ch::TryRecvError::Disconnected) => {
+                panic!("Attempted to recv a value but senders are disconnected");
+            }
+        }
+    }};
+    [<- $recv:ident] => {{
+        use $crate::ch::hooks::ChRecv;
+        match $recv.ch_recv() {
+            Ok(v) => v,
+            Err(err) => panic!("{} for `recv`.", err),
+        }
+    }};
+
+
+    [! <-? $recv:ident] => {{
+        $crate::ch::hooks::yield_now();
+        match $recv.try_recv() {
+            Ok(v) => panic!("Got {:?} when expecting senders to be closed.", v),
+            Err($crate::ch::TryRecvError::Empty) => true,  // senders still exist
+            Err($crate::ch::TryRecvError::Disconnected) => false, // no more senders
+        }
+    }};
+    [! <- $recv:ident] => {{
+        use $crate::ch::hooks::ChRecv;
+        match $recv.ch_recv() {
+            Ok(v) => panic!("Got {:?} when expecting senders to be closed.", v),
+            Err(_) => (),
+        }
+    }};
+}
+
+/// Handle an expression that could be `Err` and send it over a channel if it is.
+///
+/// This is the same as the builtin `try!` macro, except if the expression fails than the `Err` is
+/// sent on the `$send` channel and the requested action is performed.
+///
+/// Suggested possible actions:
+/// - `continue`
+/// - `return`
+/// - `break`
+/// - some expression that evaluates to a "default value" for that context.
+///
+/// # Examples
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// # fn main() {
+/// let (send_err, recv_err) = ch::unbounded();
+/// let items = &[Ok("this is alright"), Err("not ok"), Err("still not okay")];
+/// # let mut okay = 0;
+/// for item in items.iter() {
+///     let v = ch_try!(send_err, *item, continue);
+///     println!("got: {}", v);
+///     # okay += 1;
+/// }
+///
+/// drop(send_err);
+/// let errs: Vec<_> = recv_err.iter().collect();
+/// assert_eq!(vec!["not ok", "still not okay"], errs);
+/// # assert_eq!(1, okay);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! ch_try {
+    [$send:ident, $expr:expr, $action:expr] => {
+        match $expr {
+            Ok(v) => v,
+            Err(e) => {
+                ch!($send <- e);
+                $action
+            }
+        }
+    };
+}
+
+/// Select over multiple channel operations in a loop, using the same syntax as [`ch!`].
+///
+/// Each iteration of the loop blocks until exactly one of the arms is ready and then runs that
+/// arm. The supported arms are:
+///
+/// - `v = <- recv => body`: receive a value from `recv`, binding it to the pattern `v`.
+/// - `send <- value => body`: send `value` on `send`. The `value` expression is evaluated once
+///   every iteration.
+/// - `default => body`: run if none of the other arms are ready. At most one `default` arm is
+///   allowed.
+/// - `timeout(dur) => body`: run if none of the other arms became ready within `dur`, measured
+///   with the current thread's [`Clock`]. At most one `timeout` arm is allowed.
+///
+/// Unlike with `ch!`, a closed channel does _not_ panic. Instead its arm is dropped: a receive arm
+/// is dropped once its senders have all been dropped and there are no values left, and a send arm
+/// is dropped once its receivers have all been dropped. The loop exits once every `<-` arm has
+/// been dropped. Use `break` inside of an arm to exit early and `continue` to skip to the next
+/// iteration.
+///
+/// > Note: only `crossbeam_channel` channels (the ones exported by this crate) are supported.
+///
+/// [`ch!`]: macro.ch.html
+/// [`Clock`]: enum.Clock.html
+///
+/// # Examples
+///
+/// ## Example: receive until every channel is closed
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send_nums, recv_nums) = ch::unbounded();
+/// let (send_words, recv_words) = ch::unbounded();
+///
+/// spawn(move || {
+///     for i in 0..5 {
+///         ch!(send_nums <- i);
+///     }
+/// });
+/// spawn(move || ch!(send_words <- "hello"));
+///
+/// let mut total = 0;
+/// let mut words = Vec::new();
+/// select! {
+///     n = <- recv_nums => total += n,
+///     w = <- recv_words => words.push(w),
+/// }
+/// assert_eq!(10, total);
+/// assert_eq!(vec!["hello"], words);
+/// # }
+/// ```
+///
+/// ## Example: send, `default` and `timeout` arms
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(2);
+///
+/// let mut sent = 0;
+/// select! {
+///     send <- sent => sent += 1,
+///     default => break, // the channel is full
+/// }
+/// assert_eq!(2, sent);
+///
+/// let (_keep, never) = ch::unbounded::<u32>();
+/// let mut timed_out = false;
+/// select! {
+///     v = <- never => panic!("got {}", v),
+///     timeout(Duration::from_millis(10)) => {
+///         timed_out = true;
+///         break;
+///     }
+/// }
+/// assert!(timed_out);
+/// # drop(recv);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! select {
+    // ---- Split each arm into its head and body ----
+    (@split $parsed:tt [$($head:tt)*] => $body:block, $($rest:tt)*) => {
+        select!(@classify $parsed [$($head)*] $body [$($rest)*])
+    };
+    (@split $parsed:tt [$($head:tt)*] => $body:block $($rest:tt)*) => {
+        select!(@classify $parsed [$($head)*] $body [$($rest)*])
+    };
+    (@split $parsed:tt [$($head:tt)*] => $body:expr, $($rest:tt)*) => {
+        select!(@classify $parsed [$($head)*] $body [$($rest)*])
+    };
+    (@split $parsed:tt [$($head:tt)*] => $body:expr) => {
+        select!(@classify $parsed [$($head)*] $body [])
+    };
+    (@split $parsed:tt [$($head:tt)*] $next:tt $($rest:tt)*) => {
+        select!(@split $parsed [$($head)* $next] $($rest)*)
+    };
+    (@split $parsed:tt [$($head:tt)*]) => {
+        compile_error!(concat!("select! arm is missing `=> body`: ", stringify!($($head)*)))
+    };
+
+    // ---- Classify each arm, giving it unique (hygienic) identifiers ----
+    (@classify [$arms:tt $default:tt $timeout:tt] $head:tt $body:tt $rest:tt) => {
+        select!(@arm $arms $default $timeout $head $body $rest)
+    };
+    (@arm [$($arms:tt)*] $default:tt $timeout:tt [default] $body:tt [$($rest:tt)*]) => {
+        select!(@default [$($arms)*] $default $timeout $body [$($rest)*])
+    };
+    (@arm [$($arms:tt)*] $default:tt $timeout:tt [timeout($dur:expr)] $body:tt
+     [$($rest:tt)*]) => {
+        select!(@timeout [$($arms)*] $default $timeout ($dur) $body [$($rest)*])
+    };
+    (@arm [$($arms:tt)*] $default:tt $timeout:tt [$send:ident <- $value:expr] $body:tt
+     [$($rest:tt)*]) => {
+        select!(@next [$($arms)* (send slot open msg ($send, $value) $body)]
+                $default $timeout $($rest)*)
+    };
+    (@arm [$($arms:tt)*] $default:tt $timeout:tt [$v:pat = <- $recv:ident] $body:tt
+     [$($rest:tt)*]) => {
+        select!(@next [$($arms)* (recv slot open msg ($v, $recv) $body)]
+                $default $timeout $($rest)*)
+    };
+    (@arm $arms:tt $default:tt $timeout:tt [$($head:tt)*] $body:tt $rest:tt) => {
+        compile_error!(concat!("invalid select! arm: ", stringify!($($head)*)))
+    };
+
+    (@default $arms:tt [] $timeout:tt $body:tt [$($rest:tt)*]) => {
+        select!(@next $arms [$body] $timeout $($rest)*)
+    };
+    (@default $arms:tt [$($default:tt)+] $timeout:tt $body:tt $rest:tt) => {
+        compile_error!("select! accepts at most one `default` arm")
+    };
+    (@timeout $arms:tt $default:tt [] $dur:tt $body:tt [$($rest:tt)*]) => {
+        select!(@next $arms $default [$dur $body] $($rest)*)
+    };
+    (@timeout $arms:tt $default:tt [$($timeout:tt)+] $dur:tt $body:tt $rest:tt) => {
+        compile_error!("select! accepts at most one `timeout` arm")
+    };
+
+    (@next $arms:tt $default:tt $timeout:tt) => {
+        select!(@emit $arms $default $timeout)
+    };
+    (@next $arms:tt $default:tt $timeout:tt $($rest:tt)+) => {
+        select!(@split [$arms $default $timeout] [] $($rest)+)
+    };
+
+    // ---- Generate the loop ----
+    (@emit [$(($kind:ident $slot:ident $open:ident $msg:ident $args:tt $body:tt))*]
+     [$($default:tt)*] [$($timeout:tt)*]) => {
+        loop {
+            $( select!(@open $kind $open $args); )*
+            if true $(&& !$open)* {
+                break;
+            }
+
+            let mut sel = $crate::ch::Select::new();
+            select!(@timer timer [$($timeout)*]);
+            $( select!(@prepare $kind $slot $open $msg $args); )*
+            #[allow(unused_mut, unused_variables)]
+            let mut would_block = false;
+            #[allow(unused_mut, unused_variables)]
+            let mut timed_out = false;
+            loop {
+                $( select!(@probe sel $kind $slot $open $msg $args); )*
+                // The timer is never disconnected, so check the arms themselves as well.
+                if sel.disconnected() || (true $(&& select!(@closed $kind $args))*) {
+                    break;
+                }
+                select!(@probe_default sel would_block [$($default)*]);
+                select!(@probe_timeout sel timer timed_out [$($timeout)*]);
+            }
+
+            $( select!(@run $kind $slot $args $body); )*
+            select!(@run_default would_block [$($default)*]);
+            select!(@run_timeout timed_out [$($timeout)*]);
+        }
+    };
+
+    (@open recv $open:ident ($v:pat, $recv:ident)) => {
+        let $open = !($recv.is_disconnected() && $recv.is_empty());
+    };
+    (@open send $open:ident ($send:ident, $value:expr)) => {
+        let $open = !$send.is_disconnected();
+    };
+
+    (@closed recv ($v:pat, $recv:ident)) => { $recv.is_disconnected() && $recv.is_empty() };
+    (@closed send ($send:ident, $value:expr)) => { $send.is_disconnected() };
+
+    (@timer $timer:ident []) => {};
+    (@timer $timer:ident [($dur:expr) $body:tt]) => {
+        let $timer = $crate::ch::after($dur);
+    };
+
+    (@prepare recv $slot:ident $open:ident $msg:ident $args:tt) => {
+        let mut $slot = None;
+    };
+    (@prepare send $slot:ident $open:ident $msg:ident ($send:ident, $value:expr)) => {
+        let mut $slot = false;
+        let mut $msg = if $open { Some($value) } else { None };
+    };
+
+    (@probe $sel:ident recv $slot:ident $open:ident $msg:ident ($v:pat, $recv:ident)) => {
+        if $open {
+            if let Ok(v) = $sel.recv(&$recv) {
+                $slot = Some(v);
+                break;
+            }
+        }
+    };
+    (@probe $sel:ident send $slot:ident $open:ident $msg:ident ($send:ident, $value:expr)) => {
+        if let Some(m) = $msg.take() {
+            match $sel.send(&$send, m) {
+                Ok(()) => {
+                    $slot = true;
+                    break;
+                }
+                Err(err) => $msg = Some(err.into_inner()),
+            }
+        }
+    };
+    (@probe_default $sel:ident $flag:ident []) => {};
+    (@probe_default $sel:ident $flag:ident [$body:tt]) => {
+        if $sel.would_block() {
+            $flag = true;
+            break;
+        }
+    };
+    (@probe_timeout $sel:ident $timer:ident $flag:ident []) => {};
+    (@probe_timeout $sel:ident $timer:ident $flag:ident [$dur:tt $body:tt]) => {
+        if $sel.recv(&$timer).is_ok() {
+            $flag = true;
+            break;
+        }
+    };
+
+    (@run recv $slot:ident ($v:pat, $recv:ident) $body:tt) => {
+        if let Some($v) = $slot {
+            $body;
+        }
+    };
+    (@run send $slot:ident ($send:ident, $value:expr) $body:tt) => {
+        if $slot {
+            $body;
+        }
+    };
+    (@run_default $flag:ident []) => {};
+    (@run_default $flag:ident [$body:tt]) => {
+        if $flag {
+            $body;
+        }
+    };
+    (@run_timeout $flag:ident []) => {};
+    (@run_timeout $flag:ident [$dur:tt $body:tt]) => {
+        if $flag {
+            $body;
+        }
+    };
+
+    // ---- Entry point ----
+    ($($tokens:tt)+) => {
+        select!(@split [[] [] []] [] $($tokens)+)
+    };
+}
@@ -0,0 +1,452 @@
+//! A channel for sending exactly one value.
+
+use std::error;
+use std::fmt;
+use std::mem;
+use std::sync::{Condvar, MutexGuard, OnceLock};
+use std::time::Instant;
+
+use std_prelude::*;
+#[cfg(feature = "sim")]
+use sim;
+use super::hooks::{self, ChRecv, ChSend};
+use clock::Clock;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, SendError, Sender, TryRecvError};
+
+/// Create a channel for sending a single value, i.e. a reply to a request.
+///
+/// [`OneshotSender::send`] consumes the sender, so at most one value can ever be sent. If the
+/// sender is dropped without sending then the receiver gets [`OneshotRecvError::Dropped`], which
+/// can be told apart from the channel having already been received from.
+///
+/// The `OneshotReceiver` can be used with `ch!(<- recv)` like any other receiver. To use it as an
+/// arm of [`select!`] or [`select_loop!`] see [`OneshotReceiver::as_select_arm`].
+///
+/// The value is passed through a single slot rather than a channel, so creating a oneshot is
+/// cheaper than a `bounded(1)` channel. Only using it as a select arm allocates a channel.
+///
+/// [`OneshotReceiver::as_select_arm`]: struct.OneshotReceiver.html#method.as_select_arm
+/// [`OneshotSender::send`]: struct.OneshotSender.html#method.send
+/// [`OneshotRecvError::Dropped`]: enum.OneshotRecvError.html#variant.Dropped
+/// [`select!`]: ../macro.select.html
+/// [`select_loop!`]: ../macro.select_loop.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send_requests, recv_requests) = ch::unbounded::<(u64, ch::OneshotSender<u64>)>();
+/// spawn(move || {
+///     for (n, reply) in recv_requests.iter() {
+///         ch!(reply <- n * 2);
+///     }
+/// });
+///
+/// let (send_reply, recv_reply) = ch::oneshot();
+/// ch!(send_requests <- (21, send_reply));
+/// assert_eq!(42, ch!(<- recv_reply));
+///
+/// // the sender was consumed when the reply was sent
+/// assert_eq!(Err(ch::OneshotRecvError::Disconnected), recv_reply.recv());
+///
+/// // dropping the sender without replying can be detected
+/// let (send_reply, recv_reply) = ch::oneshot::<u64>();
+/// drop(send_reply);
+/// assert_eq!(Err(ch::OneshotRecvError::Dropped), recv_reply.recv());
+///
+/// // as a select arm `None` means the sender was dropped
+/// let (send_reply, recv_reply) = ch::oneshot::<u64>();
+/// drop(send_reply);
+/// select_loop! {
+///     recv(recv_reply.as_select_arm(), reply) => assert_eq!(None, reply),
+/// }
+/// # }
+/// ```
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            slot: Slot::Empty,
+            armed: false,
+            arm: None,
+            receiver_dropped: false,
+        }),
+        ready: Condvar::new(),
+    });
+    (
+        OneshotSender {
+            shared: shared.clone(),
+            sent: false,
+        },
+        OneshotReceiver {
+            shared,
+            arm: OnceLock::new(),
+        },
+    )
+}
+
+/// What the sender has passed to the receiver.
+enum Slot<T> {
+    /// Nothing has been sent yet.
+    Empty,
+    Sent(T),
+    /// The sender was dropped without sending.
+    Dropped,
+    /// The result was received, or moved to the select arm.
+    Taken,
+}
+
+struct State<T> {
+    slot: Slot<T>,
+    /// Whether the receiver has created its select arm, after which the slot is unused.
+    armed: bool,
+    /// The sending half of the select arm, if the sender has not completed yet.
+    arm: Option<Sender<Option<T>>>,
+    receiver_dropped: bool,
+}
+
+impl<T> State<T> {
+    /// Take the result out of the slot, or `None` if nothing has been sent yet.
+    fn take(&mut self) -> Option<Result<T, OneshotRecvError>> {
+        match mem::replace(&mut self.slot, Slot::Taken) {
+            Slot::Empty => {
+                self.slot = Slot::Empty;
+                None
+            }
+            Slot::Sent(v) => Some(Ok(v)),
+            Slot::Dropped => Some(Err(OneshotRecvError::Dropped)),
+            Slot::Taken => Some(Err(OneshotRecvError::Disconnected)),
+        }
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Notified when the sender completes or the select arm is created.
+    ready: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().expect("oneshot poisoned")
+    }
+}
+
+/// The sending half of a [`oneshot`] channel.
+///
+/// [`oneshot`]: fn.oneshot.html
+pub struct OneshotSender<T> {
+    shared: Arc<Shared<T>>,
+    sent: bool,
+}
+
+impl<T> OneshotSender<T> {
+    /// Send the value, consuming the sender.
+    ///
+    /// Returns the value in the `Err` if the receiver has been dropped. This never blocks.
+    pub fn send(mut self, value: T) -> Result<(), SendError<T>> {
+        self.sent = true;
+        self.complete(Some(value))
+            .map_err(|v| SendError(v.expect("value was just sent")))
+    }
+
+    /// Returns `true` if the receiver has been dropped.
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.lock().receiver_dropped
+    }
+
+    /// Pass the value (or `None` if the sender was dropped) to the receiver.
+    fn complete(&self, value: Option<T>) -> Result<(), Option<T>> {
+        let mut state = self.shared.lock();
+        if state.receiver_dropped {
+            return Err(value);
+        }
+        match state.arm.take() {
+            // The arm only ever holds this value, so it cannot be full.
+            Some(arm) => {
+                let _ = arm.try_send(value);
+            }
+            None => {
+                state.slot = match value {
+                    Some(v) => Slot::Sent(v),
+                    None => Slot::Dropped,
+                };
+                self.shared.ready.notify_all();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> ChSend for OneshotSender<T> {
+    type Item = T;
+    type Error = SendError<T>;
+
+    fn ch_send(self, value: T) -> Result<(), SendError<T>> {
+        hooks::yield_now();
+        self.send(value)
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        if !self.sent {
+            // Let the receiver know that no value is coming.
+            let _ = self.complete(None);
+        }
+    }
+}
+
+impl<T> fmt::Debug for OneshotSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OneshotSender { .. }")
+    }
+}
+
+/// The receiving half of a [`oneshot`] channel.
+///
+/// [`oneshot`]: fn.oneshot.html
+pub struct OneshotReceiver<T> {
+    shared: Arc<Shared<T>>,
+    arm: OnceLock<Receiver<Option<T>>>,
+}
+
+/// The outcome of waiting on the slot.
+enum Wait<T> {
+    Done(Result<T, OneshotRecvError>),
+    Timeout,
+    /// The select arm was created, so the result will be sent on it instead.
+    Armed,
+}
+
+impl<T> OneshotReceiver<T> {
+    /// Block until the value is received.
+    pub fn recv(&self) -> Result<T, OneshotRecvError> {
+        match self.wait(None) {
+            Wait::Done(result) => result,
+            Wait::Timeout => unreachable!("there is no deadline"),
+            Wait::Armed => match self.as_select_arm().recv() {
+                Ok(Some(v)) => Ok(v),
+                Ok(None) => Err(OneshotRecvError::Dropped),
+                Err(_) => Err(OneshotRecvError::Disconnected),
+            },
+        }
+    }
+
+    /// Attempt to receive the value without blocking.
+    ///
+    /// This returns `TryRecvError::Disconnected` both when the sender was dropped without sending
+    /// and when the value has already been received. Use [`recv`] to tell them apart.
+    ///
+    /// [`recv`]: #method.recv
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.poll() {
+            Some(Ok(v)) => Ok(v),
+            Some(Err(_)) => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Block until the value is received or `timeout` has elapsed according to the current
+    /// thread's [`Clock`].
+    ///
+    /// With a [`ManualClock`] this creates the select arm, see [`as_select_arm`].
+    ///
+    /// [`Clock`]: ../enum.Clock.html
+    /// [`ManualClock`]: ../struct.ManualClock.html
+    /// [`as_select_arm`]: #method.as_select_arm
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// let (send, recv) = ch::oneshot::<u32>();
+    /// let timeout = Duration::from_millis(10);
+    /// assert_eq!(Err(ch::OneshotRecvTimeoutError::Timeout), recv.recv_timeout(timeout));
+    ///
+    /// ch!(send <- 7);
+    /// assert_eq!(Ok(7), recv.recv_timeout(timeout));
+    /// # }
+    /// ```
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, OneshotRecvTimeoutError> {
+        let clock = Clock::current();
+        if let Clock::Real = clock {
+            // A deadline which overflows is never reached.
+            match self.wait(Instant::now().checked_add(timeout)) {
+                Wait::Done(Ok(v)) => return Ok(v),
+                Wait::Done(Err(OneshotRecvError::Dropped)) => {
+                    return Err(OneshotRecvTimeoutError::Dropped)
+                }
+                Wait::Done(Err(OneshotRecvError::Disconnected)) => {
+                    return Err(OneshotRecvTimeoutError::Disconnected)
+                }
+                Wait::Timeout => return Err(OneshotRecvTimeoutError::Timeout),
+                Wait::Armed => {}
+            }
+        }
+        match clock.recv_timeout(self.as_select_arm(), timeout) {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => Err(OneshotRecvTimeoutError::Dropped),
+            Err(RecvTimeoutError::Timeout) => Err(OneshotRecvTimeoutError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(OneshotRecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// A channel for use as an arm of [`select!`] or [`select_loop!`].
+    ///
+    /// It receives `Some(value)` once the value is sent, or `None` if the sender was dropped
+    /// without sending. The first call allocates a `bounded(1)` channel, which the value is
+    /// passed through from then on.
+    ///
+    /// [`select!`]: ../macro.select.html
+    /// [`select_loop!`]: ../macro.select_loop.html
+    pub fn as_select_arm(&self) -> &Receiver<Option<T>> {
+        self.arm.get_or_init(|| {
+            let (send, recv) = bounded(1);
+            let mut state = self.shared.lock();
+            state.armed = true;
+            // The arm is empty, so sending cannot fail. If the result was already received then
+            // dropping `send` disconnects the arm.
+            match mem::replace(&mut state.slot, Slot::Taken) {
+                Slot::Empty => state.arm = Some(send),
+                Slot::Sent(v) => {
+                    let _ = send.try_send(Some(v));
+                }
+                Slot::Dropped => {
+                    let _ = send.try_send(None);
+                }
+                Slot::Taken => {}
+            }
+            self.shared.ready.notify_all();
+            recv
+        })
+    }
+
+    /// Wait until the result is in the slot, the `deadline` (if any) passes or the arm is
+    /// created.
+    fn wait(&self, deadline: Option<Instant>) -> Wait<T> {
+        let mut state = self.shared.lock();
+        while !state.armed {
+            if let Some(result) = state.take() {
+                return Wait::Done(result);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Wait::Timeout;
+                    }
+                    self.shared
+                        .ready
+                        .wait_timeout(state, deadline - now)
+                        .expect("oneshot poisoned")
+                        .0
+                }
+                None => self.shared.ready.wait(state).expect("oneshot poisoned"),
+            };
+        }
+        Wait::Armed
+    }
+
+    /// Receive the result without blocking, or `None` if nothing has been sent yet.
+    fn poll(&self) -> Option<Result<T, OneshotRecvError>> {
+        {
+            let mut state = self.shared.lock();
+            if !state.armed {
+                return state.take();
+            }
+        }
+        match self.as_select_arm().try_recv() {
+            Ok(Some(v)) => Some(Ok(v)),
+            Ok(None) => Some(Err(OneshotRecvError::Dropped)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(OneshotRecvError::Disconnected)),
+        }
+    }
+}
+
+impl<T> ChRecv for &OneshotReceiver<T> {
+    type Item = T;
+    type Error = OneshotRecvError;
+
+    fn ch_recv(self) -> Result<T, OneshotRecvError> {
+        #[cfg(feature = "sim")]
+        {
+            if let Some(task) = sim::current() {
+                return task.block_on("recv", || self.poll());
+            }
+        }
+        self.recv()
+    }
+}
+
+impl<T> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiver_dropped = true;
+        state.slot = Slot::Taken;
+        state.arm = None;
+    }
+}
+
+impl<T> fmt::Debug for OneshotReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OneshotReceiver { .. }")
+    }
+}
+
+/// An error returned from [`OneshotReceiver::recv`].
+///
+/// [`OneshotReceiver::recv`]: struct.OneshotReceiver.html#method.recv
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OneshotRecvError {
+    /// The sender was dropped without sending a value.
+    Dropped,
+    /// The value was already received.
+    Disconnected,
+}
+
+impl fmt::Display for OneshotRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OneshotRecvError::Dropped => f.write_str("oneshot sender was dropped without sending"),
+            OneshotRecvError::Disconnected => {
+                f.write_str("receiving on an empty and disconnected channel")
+            }
+        }
+    }
+}
+
+impl error::Error for OneshotRecvError {}
+
+/// An error returned from [`OneshotReceiver::recv_timeout`].
+///
+/// [`OneshotReceiver::recv_timeout`]: struct.OneshotReceiver.html#method.recv_timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OneshotRecvTimeoutError {
+    /// No value was sent before the timeout elapsed.
+    Timeout,
+    /// The sender was dropped without sending a value.
+    Dropped,
+    /// The value was already received.
+    Disconnected,
+}
+
+impl fmt::Display for OneshotRecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OneshotRecvTimeoutError::Timeout => f.write_str("timed out waiting on channel"),
+            OneshotRecvTimeoutError::Dropped => {
+                f.write_str("oneshot sender was dropped without sending")
+            }
+            OneshotRecvTimeoutError::Disconnected => {
+                f.write_str("receiving on an empty and disconnected channel")
+            }
+        }
+    }
+}
+
+impl error::Error for OneshotRecvTimeoutError {}
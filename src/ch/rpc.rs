@@ -0,0 +1,299 @@
+//! Channels for requests which each get a single response.
+
+use std::error;
+use std::fmt;
+
+use std_prelude::*;
+use super::hooks::{self, ChSend};
+use super::DropQueued;
+use clock::Clock;
+use super::{bounded, oneshot, IntoIter, Iter, OneshotRecvError, OneshotRecvTimeoutError,
+            OneshotSender, Receiver, RecvError, SendError, SendTimeoutError, Sender};
+
+type Request<Req, Resp> = (Req, Responder<Resp>);
+
+/// Create a channel for making requests which each get a single response.
+///
+/// This replaces the pattern of sending `(Request, Sender<Response>)` tuples: the [`Client`]
+/// sends the request with a [`Responder`] attached and waits for the response, while the
+/// [`Server`] receives `(Request, Responder)` pairs like any other channel.
+///
+/// The request channel can hold `cap` requests before `call` blocks.
+///
+/// [`Client`]: struct.Client.html
+/// [`Responder`]: struct.Responder.html
+/// [`Server`]: struct.Server.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (client, server) = ch::rpc::<u64, u64>(16);
+/// spawn(move || {
+///     for (n, responder) in server {
+///         if n == 0 {
+///             // dropping the responder lets the caller know no response is coming
+///             continue;
+///         }
+///         ch!(responder <- n * 2);
+///     }
+/// });
+///
+/// assert_eq!(42, client.call(21));
+///
+/// let timeout = Duration::from_secs(1);
+/// assert_eq!(Ok(4), client.call_timeout(2, timeout));
+/// assert_eq!(Err(ch::RpcError::Dropped), client.call_timeout(0, timeout));
+/// # }
+/// ```
+pub fn rpc<Req, Resp>(cap: usize) -> (Client<Req, Resp>, Server<Req, Resp>) {
+    let (send, recv) = bounded(cap);
+    let queued = Arc::new(DropQueued(recv.clone()));
+    (Client { send }, Server { recv, queued })
+}
+
+/// The calling half of an [`rpc`] channel.
+///
+/// [`rpc`]: fn.rpc.html
+pub struct Client<Req, Resp> {
+    send: Sender<Request<Req, Resp>>,
+}
+
+impl<Req, Resp> Client<Req, Resp> {
+    /// Send the request and block until the response is received.
+    ///
+    /// # Panics
+    /// Panics (with the same messages as [`ch!`]) if the server is disconnected or the responder
+    /// was dropped without responding.
+    ///
+    /// [`ch!`]: ../macro.ch.html
+    pub fn call(&self, req: Req) -> Resp {
+        let (send_resp, recv_resp) = oneshot();
+        if let Err(SendError(_)) = self.send.send((req, Responder { send: send_resp })) {
+            panic!("{} for `send`.", RpcError::Disconnected);
+        }
+        match recv_resp.recv() {
+            Ok(resp) => resp,
+            // The response channel is new, so it can only be disconnected if the responder was
+            // dropped without letting it know.
+            Err(OneshotRecvError::Dropped) | Err(OneshotRecvError::Disconnected) => {
+                panic!("{} for `recv`.", RpcError::Dropped)
+            }
+        }
+    }
+
+    /// Send the request and wait up to `timeout` (in total) for the response.
+    ///
+    /// The timeout is measured by the current thread's [`Clock`].
+    ///
+    /// [`Clock`]: ../enum.Clock.html
+    pub fn call_timeout(&self, req: Req, timeout: Duration) -> Result<Resp, RpcError> {
+        let clock = Clock::current();
+        let deadline = clock.now() + timeout;
+        let (send_resp, recv_resp) = oneshot();
+        match clock.send_timeout(&self.send, (req, Responder { send: send_resp }), timeout) {
+            Ok(()) => {}
+            Err(SendTimeoutError::Timeout(_)) => return Err(RpcError::Timeout),
+            Err(SendTimeoutError::Disconnected(_)) => return Err(RpcError::Disconnected),
+        }
+        let remaining = deadline.saturating_duration_since(clock.now());
+        recv_resp.recv_timeout(remaining).map_err(|err| match err {
+            OneshotRecvTimeoutError::Timeout => RpcError::Timeout,
+            OneshotRecvTimeoutError::Dropped | OneshotRecvTimeoutError::Disconnected => {
+                RpcError::Dropped
+            }
+        })
+    }
+
+    /// Returns `true` if the server has been dropped.
+    pub fn is_disconnected(&self) -> bool {
+        self.send.is_disconnected()
+    }
+}
+
+impl<Req, Resp> Clone for Client<Req, Resp> {
+    fn clone(&self) -> Client<Req, Resp> {
+        Client {
+            send: self.send.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Client<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Client { .. }")
+    }
+}
+
+/// The serving half of an [`rpc`] channel, which receives `(Req, Responder)` pairs.
+///
+/// The server is disconnected once every [`Client`] has been dropped. Once every `Server` (and
+/// iterator over it) has been dropped, the requests which were still queued are dropped too, so
+/// their callers get [`RpcError::Dropped`] instead of waiting forever.
+///
+/// [`rpc`]: fn.rpc.html
+/// [`Client`]: struct.Client.html
+/// [`RpcError::Dropped`]: enum.RpcError.html#variant.Dropped
+///
+/// # Examples
+/// ```rust
+/// # extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (client, server) = ch::rpc::<u64, u64>(4);
+/// let th = spawn(move || client.call_timeout(1, Duration::from_secs(60)));
+///
+/// // the server stops without handling the queued request
+/// while server.receiver().is_empty() {
+///     sleep_ms(1);
+/// }
+/// drop(server);
+/// assert_eq!(Err(ch::RpcError::Dropped), th.finish());
+/// # }
+/// ```
+pub struct Server<Req, Resp> {
+    recv: Receiver<Request<Req, Resp>>,
+    /// Shared by the clones, so that the queue is only emptied once the last one is dropped.
+    queued: Arc<DropQueued<Request<Req, Resp>>>,
+}
+
+impl<Req, Resp> Server<Req, Resp> {
+    /// Block until a request is received.
+    pub fn recv(&self) -> Result<(Req, Responder<Resp>), RecvError> {
+        self.recv.recv()
+    }
+
+    /// Iterate over the requests until every client is dropped.
+    pub fn iter(&self) -> Iter<'_, (Req, Responder<Resp>)> {
+        self.recv.iter()
+    }
+
+    /// The underlying channel, i.e. for use in [`select!`].
+    ///
+    /// [`select!`]: ../macro.select.html
+    pub fn receiver(&self) -> &Receiver<(Req, Responder<Resp>)> {
+        &self.recv
+    }
+}
+
+impl<Req, Resp> Clone for Server<Req, Resp> {
+    fn clone(&self) -> Server<Req, Resp> {
+        Server {
+            recv: self.recv.clone(),
+            queued: self.queued.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> IntoIterator for Server<Req, Resp> {
+    type Item = (Req, Responder<Resp>);
+    type IntoIter = ServerIntoIter<Req, Resp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ServerIntoIter {
+            iter: self.recv.into_iter(),
+            _queued: self.queued,
+        }
+    }
+}
+
+/// An iterator over the requests of a [`Server`], which it owns.
+///
+/// [`Server`]: struct.Server.html
+pub struct ServerIntoIter<Req, Resp> {
+    iter: IntoIter<Request<Req, Resp>>,
+    _queued: Arc<DropQueued<Request<Req, Resp>>>,
+}
+
+impl<Req, Resp> Iterator for ServerIntoIter<Req, Resp> {
+    type Item = (Req, Responder<Resp>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+impl<Req, Resp> fmt::Debug for ServerIntoIter<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerIntoIter { .. }")
+    }
+}
+
+impl<'a, Req, Resp> IntoIterator for &'a Server<Req, Resp> {
+    type Item = (Req, Responder<Resp>);
+    type IntoIter = Iter<'a, (Req, Responder<Resp>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.recv.iter()
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Server<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Server { .. }")
+    }
+}
+
+/// Sends the response to a single [`rpc`] request.
+///
+/// If the responder is dropped without sending, the caller gets [`RpcError::Dropped`].
+///
+/// [`rpc`]: fn.rpc.html
+/// [`RpcError::Dropped`]: enum.RpcError.html#variant.Dropped
+#[derive(Debug)]
+pub struct Responder<Resp> {
+    send: OneshotSender<Resp>,
+}
+
+impl<Resp> Responder<Resp> {
+    /// Send the response, consuming the responder.
+    ///
+    /// Returns the response in the `Err` if the caller is no longer waiting (i.e. it timed out).
+    /// This never blocks.
+    pub fn send(self, resp: Resp) -> Result<(), SendError<Resp>> {
+        self.send.send(resp)
+    }
+
+    /// Returns `true` if the caller is no longer waiting for the response.
+    pub fn is_disconnected(&self) -> bool {
+        self.send.is_disconnected()
+    }
+}
+
+impl<Resp> ChSend for Responder<Resp> {
+    type Item = Resp;
+    type Error = SendError<Resp>;
+
+    fn ch_send(self, resp: Resp) -> Result<(), SendError<Resp>> {
+        hooks::yield_now();
+        self.send(resp)
+    }
+}
+
+/// An error returned from [`Client::call_timeout`].
+///
+/// [`Client::call_timeout`]: struct.Client.html#method.call_timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcError {
+    /// The server was dropped, so the request could not be sent.
+    Disconnected,
+    /// The responder was dropped without responding.
+    Dropped,
+    /// No response was received before the timeout elapsed.
+    Timeout,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RpcError::Disconnected => "sending on a disconnected channel",
+            RpcError::Dropped => "responder was dropped without responding",
+            RpcError::Timeout => "timed out waiting on channel",
+        })
+    }
+}
+
+impl error::Error for RpcError {}
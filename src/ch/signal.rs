@@ -0,0 +1,379 @@
+//! Unix signals delivered over channels.
+
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::process;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicI32;
+
+use libc;
+use std_prelude::*;
+use super::{unbounded, Receiver, Sender};
+
+/// A unix signal which can be received with [`signals`].
+///
+/// [`signals`]: fn.signals.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Signal {
+    /// `SIGINT`, sent by Ctrl-C.
+    Interrupt,
+    /// `SIGTERM`, the polite request to exit.
+    Terminate,
+    /// `SIGHUP`, often used to request reloading configuration.
+    Hangup,
+    /// `SIGQUIT`, sent by Ctrl-\.
+    Quit,
+    /// `SIGUSR1`.
+    User1,
+    /// `SIGUSR2`.
+    User2,
+}
+
+/// `SIGINT`, sent by Ctrl-C.
+pub const SIGINT: Signal = Signal::Interrupt;
+/// `SIGTERM`, the polite request to exit.
+pub const SIGTERM: Signal = Signal::Terminate;
+/// `SIGHUP`, often used to request reloading configuration.
+pub const SIGHUP: Signal = Signal::Hangup;
+/// `SIGQUIT`, sent by Ctrl-\.
+pub const SIGQUIT: Signal = Signal::Quit;
+/// `SIGUSR1`.
+pub const SIGUSR1: Signal = Signal::User1;
+/// `SIGUSR2`.
+pub const SIGUSR2: Signal = Signal::User2;
+
+impl Signal {
+    fn as_raw(self) -> c_int {
+        match self {
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::User1 => libc::SIGUSR1,
+            Signal::User2 => libc::SIGUSR2,
+        }
+    }
+
+    fn from_raw(raw: c_int) -> Option<Signal> {
+        [
+            Signal::Interrupt,
+            Signal::Terminate,
+            Signal::Hangup,
+            Signal::Quit,
+            Signal::User1,
+            Signal::User2,
+        ].iter()
+            .cloned()
+            .find(|s| s.as_raw() == raw)
+    }
+
+    /// Send this signal to the current process.
+    pub fn raise(self) {
+        unsafe {
+            libc::raise(self.as_raw());
+        }
+    }
+}
+
+/// Receive the given signals over a channel.
+///
+/// The first call for a signal replaces its default action (i.e. exiting on `SIGINT`) for the
+/// rest of the process. Every channel subscribed to a signal receives it, until the channel is
+/// dropped.
+///
+/// The returned channel is an ordinary `Receiver`, so it can be used as an arm of
+/// [`select_loop!`] or [`select!`], i.e. to stop feeding a pipeline when `SIGTERM` is received.
+///
+/// [`select_loop!`]: ../macro.select_loop.html
+/// [`select!`]: ../macro.select.html
+///
+/// # Panics
+/// Panics if the signal handlers could not be installed.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let signals = ch::signals(&[ch::SIGUSR1, ch::SIGHUP]);
+/// let ticks = ch::tick(Duration::from_millis(10));
+///
+/// ch::SIGUSR1.raise();
+///
+/// 'outer: loop {
+///     select_loop! {
+///         recv(signals, sig) => {
+///             assert_eq!(ch::SIGUSR1, sig);
+///             break 'outer;
+///         }
+///         recv(ticks, _) => println!("working..."),
+///     }
+/// }
+/// # }
+/// ```
+pub fn signals(signals: &[Signal]) -> Receiver<Signal> {
+    let (send, recv) = unbounded();
+    let registry = registry();
+    let mut subscribers = registry.subscribers.lock().expect("signals poisoned");
+    let mut installed = registry.installed.lock().expect("signals poisoned");
+    for &sig in signals {
+        if !installed.contains(&sig) {
+            install(sig).expect("failed to install the signal handler");
+            installed.push(sig);
+        }
+    }
+    subscribers.push(Subscriber {
+        signals: signals.to_vec(),
+        send,
+    });
+    recv
+}
+
+/// Drain the pipeline on the first Ctrl-C (`SIGINT`) and exit on the second.
+///
+/// The returned [`Drain`] starts draining on the first `SIGINT` or `SIGTERM`. Sources should
+/// check it and stop producing values, so that the rest of the pipeline finishes the values
+/// already sent and then shuts down normally. A second `SIGINT` exits the process immediately
+/// (with code 130), for when draining takes too long.
+///
+/// [`Drain`]: struct.Drain.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let drain = ch::drain_on_interrupt();
+/// let (send, recv) = ch::bounded(16);
+/// let source = {
+///     take!(=drain);
+///     spawn(move || {
+///         let mut sent = 0;
+///         while !drain.is_draining() {
+///             ch!(send <- sent);
+///             sent += 1;
+///         }
+///         sent
+///     })
+/// };
+///
+/// let mut received = 0;
+/// for _ in recv.iter() {
+///     if received == 100 {
+///         // i.e. the user pressed Ctrl-C
+///         ch::SIGINT.raise();
+///     }
+///     received += 1;
+/// }
+/// assert_eq!(source.finish(), received);
+/// # }
+/// ```
+pub fn drain_on_interrupt() -> Drain {
+    let signals = signals(&[SIGINT, SIGTERM]);
+    let (send_drain, recv_drain) = unbounded::<()>();
+    spawn(move || {
+        if signals.recv().is_err() {
+            return;
+        }
+        drop(send_drain);
+        for sig in signals.iter() {
+            if sig == SIGINT {
+                process::exit(130);
+            }
+        }
+    });
+    Drain { recv: recv_drain }
+}
+
+/// Returned by [`drain_on_interrupt`] to tell the pipeline to stop producing values.
+///
+/// [`drain_on_interrupt`]: fn.drain_on_interrupt.html
+#[derive(Debug, Clone)]
+pub struct Drain {
+    recv: Receiver<()>,
+}
+
+impl Drain {
+    /// Returns `true` once the pipeline should drain.
+    pub fn is_draining(&self) -> bool {
+        self.recv.is_disconnected()
+    }
+
+    /// A channel which is disconnected once the pipeline should drain, for use in [`select!`].
+    ///
+    /// Nothing is ever sent on the channel.
+    ///
+    /// [`select!`]: ../macro.select.html
+    pub fn receiver(&self) -> &Receiver<()> {
+        &self.recv
+    }
+}
+
+struct Subscriber {
+    signals: Vec<Signal>,
+    send: Sender<Signal>,
+}
+
+struct Registry {
+    subscribers: Mutex<Vec<Subscriber>>,
+    installed: Mutex<Vec<Signal>>,
+}
+
+/// The write end of the pipe which the signal handler writes to.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// Get the registry, creating the pipe and the thread which dispatches signals on first use.
+fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            panic!(
+                "failed to create the signal pipe: {}",
+                io::Error::last_os_error()
+            );
+        }
+        unsafe {
+            // The signal handler must never block.
+            libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+            libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(fds[1], libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        PIPE.store(fds[1], AtomicOrdering::SeqCst);
+        let read = fds[0];
+        spawn(move || dispatch(read));
+        Registry {
+            subscribers: Mutex::new(Vec::new()),
+            installed: Mutex::new(Vec::new()),
+        }
+    })
+}
+
+/// Read signals from the pipe, sending each to its subscribers.
+fn dispatch(read: c_int) {
+    let mut byte = 0_u8;
+    loop {
+        let n = unsafe { libc::read(read, &mut byte as *mut u8 as *mut c_void, 1) };
+        if n != 1 {
+            if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        let Some(sig) = Signal::from_raw(c_int::from(byte)) else {
+            continue;
+        };
+        let registry = REGISTRY.get().expect("the registry creates the pipe");
+        let mut subscribers = registry.subscribers.lock().expect("signals poisoned");
+        // Drop the subscribers whose channel has been dropped.
+        subscribers.retain(|s| !s.signals.contains(&sig) || s.send.send(sig).is_ok());
+    }
+}
+
+extern "C" fn handle(raw: c_int) {
+    // Only async-signal-safe functions can be called here, so the signal is handed off to the
+    // dispatch thread.
+    let byte = raw as u8;
+    unsafe {
+        // The interrupted code may be about to read `errno`, which `write` can change.
+        let errno = errno_location();
+        let saved = if errno.is_null() { 0 } else { *errno };
+        libc::write(
+            PIPE.load(AtomicOrdering::SeqCst),
+            &byte as *const u8 as *const c_void,
+            1,
+        );
+        if !errno.is_null() {
+            *errno = saved;
+        }
+    }
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "emscripten",
+    target_os = "dragonfly",
+    target_os = "hurd",
+    target_os = "redox",
+    target_os = "l4re"
+))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__errno_location()
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__error()
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(any(target_os = "android", target_os = "openbsd", target_os = "netbsd"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__errno()
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::___errno()
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(target_os = "haiku")]
+unsafe fn errno_location() -> *mut c_int {
+    libc::_errnop()
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(target_os = "aix")]
+unsafe fn errno_location() -> *mut c_int {
+    libc::_Errno()
+}
+
+/// The location of the current thread's `errno`.
+#[cfg(target_os = "nto")]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__get_errno_ptr()
+}
+
+/// Null where the location of `errno` is not known, in which case it is not restored after a
+/// signal. `write` only changes it when it fails, which is rare.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "emscripten",
+    target_os = "dragonfly",
+    target_os = "hurd",
+    target_os = "redox",
+    target_os = "l4re",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "android",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+    target_os = "aix",
+    target_os = "nto"
+)))]
+unsafe fn errno_location() -> *mut c_int {
+    ::std::ptr::null_mut()
+}
+
+fn install(sig: Signal) -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = ::std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(sig.as_raw(), &action, ::std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
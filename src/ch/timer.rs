@@ -0,0 +1,224 @@
+//! Timer channels driven by a single shared thread.
+
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, OnceLock};
+use std::time::Instant;
+
+use std_prelude::*;
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use clock::Clock;
+
+/// Create a channel which receives the current `Instant` once, after `dur` has elapsed.
+///
+/// All timer channels are driven by a single shared thread, so this is cheap to call often (i.e.
+/// once per iteration of a loop). This makes it easy to use timeouts as an arm of
+/// [`select_loop!`]. The time is that of the current thread's [`Clock`].
+///
+/// A timer whose `Receiver` is dropped is discarded without waiting for its deadline. If `dur`
+/// is too long to represent then the channel never receives.
+///
+/// [`select_loop!`]: ../macro.select_loop.html
+/// [`Clock`]: ../enum.Clock.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (_send, recv) = ch::unbounded::<u32>();
+/// let timeout = ch::after(Duration::from_millis(50));
+///
+/// select_loop! {
+///     recv(recv, v) => panic!("unexpected value {}", v),
+///     recv(timeout, _) => println!("gave up waiting"),
+/// }
+/// # }
+/// ```
+pub fn after(dur: Duration) -> Receiver<Instant> {
+    Clock::current().after(dur)
+}
+
+/// `after` using the real clock.
+pub(crate) fn real_after(dur: Duration) -> Receiver<Instant> {
+    let (send, recv) = bounded(1);
+    timer().schedule(Entry {
+        deadline: Instant::now().checked_add(dur),
+        period: None,
+        send,
+    });
+    recv
+}
+
+/// Create a channel which receives the current `Instant` every `dur`.
+///
+/// The channel only buffers a single tick. If the receiver falls behind then ticks are dropped
+/// instead of piling up. The ticks stop when the `Receiver` is dropped. The time is that of the
+/// current thread's [`Clock`].
+///
+/// [`Clock`]: ../enum.Clock.html
+///
+/// # Panics
+/// Panics if `dur` is zero.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::unbounded();
+/// spawn(move || {
+///     sleep_ms(100);
+///     ch!(send <- "done");
+/// });
+///
+/// let heartbeat = ch::tick(Duration::from_millis(10));
+/// let mut beats = 0;
+/// 'outer: loop {
+///     select_loop! {
+///         recv(heartbeat, _) => beats += 1,
+///         recv(recv, msg) => {
+///             assert_eq!("done", msg);
+///             break 'outer;
+///         }
+///     }
+/// }
+/// assert!(beats > 0);
+/// # }
+/// ```
+pub fn tick(dur: Duration) -> Receiver<Instant> {
+    Clock::current().tick(dur)
+}
+
+/// `tick` using the real clock.
+pub(crate) fn real_tick(dur: Duration) -> Receiver<Instant> {
+    let (send, recv) = bounded(1);
+    timer().schedule(Entry {
+        deadline: Instant::now().checked_add(dur),
+        period: Some(dur),
+        send,
+    });
+    recv
+}
+
+struct Entry {
+    /// `None` if the timer never fires, because its deadline overflowed.
+    deadline: Option<Instant>,
+    period: Option<Duration>,
+    send: Sender<Instant>,
+}
+
+impl Entry {
+    /// Fire the timer, returning the entry if it should be rescheduled.
+    fn fire(mut self, now: Instant) -> Option<Entry> {
+        match self.send.try_send(now) {
+            Err(TrySendError::Disconnected(_)) => return None,
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+        }
+        let period = self.period?;
+        self.deadline = match self.deadline.and_then(|d| d.checked_add(period)) {
+            // The thread fell behind, skip the missed ticks.
+            Some(deadline) if deadline <= now => now.checked_add(period),
+            deadline => deadline,
+        };
+        Some(self)
+    }
+
+    /// Order by deadline, with the entries which never fire last.
+    fn key(&self) -> (bool, Option<Instant>) {
+        (self.deadline.is_none(), self.deadline)
+    }
+}
+
+// `BinaryHeap` is a max-heap, so order entries by their deadline in reverse.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> cmp::Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Entry {}
+
+/// The heap is pruned once it holds at least this many entries.
+const MIN_PRUNE: usize = 64;
+
+struct Entries {
+    heap: BinaryHeap<Entry>,
+    /// Prune the entries whose `Receiver` was dropped once the heap reaches this size.
+    prune_at: usize,
+}
+
+struct Timer {
+    entries: Mutex<Entries>,
+    wake: Condvar,
+}
+
+impl Timer {
+    fn schedule(&self, entry: Entry) {
+        let mut entries = self.entries.lock().expect("timer poisoned");
+        if entries.heap.len() >= entries.prune_at {
+            // Otherwise calling `after` in a loop grows the heap until the deadlines pass. The
+            // limit doubles with the live entries, so pruning is amortized over the schedules.
+            entries.heap.retain(|e| !e.send.is_disconnected());
+            entries.prune_at = cmp::max(2 * entries.heap.len(), MIN_PRUNE);
+        }
+        entries.heap.push(entry);
+        self.wake.notify_one();
+    }
+
+    fn run(&self) {
+        let mut entries = self.entries.lock().expect("timer poisoned");
+        loop {
+            let now = Instant::now();
+            while entries
+                .heap
+                .peek()
+                .is_some_and(|e| e.deadline.is_some_and(|d| d <= now))
+            {
+                let entry = entries.heap.pop().expect("peeked");
+                if let Some(entry) = entry.fire(now) {
+                    entries.heap.push(entry);
+                }
+            }
+
+            entries = match entries.heap.peek().and_then(|e| e.deadline) {
+                Some(deadline) => {
+                    self.wake
+                        .wait_timeout(entries, deadline - now)
+                        .expect("timer poisoned")
+                        .0
+                }
+                None => self.wake.wait(entries).expect("timer poisoned"),
+            };
+        }
+    }
+}
+
+/// Get the shared timer, starting its thread if this is the first use.
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER.get_or_init(|| {
+        spawn(|| timer().run());
+        Timer {
+            entries: Mutex::new(Entries {
+                heap: BinaryHeap::new(),
+                prune_at: MIN_PRUNE,
+            }),
+            wake: Condvar::new(),
+        }
+    })
+}
@@ -0,0 +1,467 @@
+//! The clock used for sleeping, timeouts and timer channels.
+//!
+//! Tests of timeout and retry logic can use a [`ManualClock`] so that they run instantly and do
+//! not depend on how fast the machine running them is.
+//!
+//! [`ManualClock`]: struct.ManualClock.html
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::{Condvar, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+use std_prelude::*;
+use ch::timer;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Select, SendTimeoutError, Sender,
+                        TryRecvError, TrySendError};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Clock>> = const { RefCell::new(None) };
+}
+
+/// The clock which [`sleep`], [`sleep_ms`], channel timeouts and timer channels use.
+///
+/// Each thread has a current clock, which is `Clock::Real` unless another clock was entered with
+/// [`Clock::enter`]. Threads started with [`spawn`] or [`spawn_promise`] use the clock of the
+/// thread which spawned them, as do the threads of stages such as [`retry`], the actor thread of
+/// [`spawn_actor`], the children of a [`Supervisor`] and [`Receiver`] extensions such as
+/// `debounce`. The workers of a [`Pool`] use the clock of the thread which built it.
+///
+/// The `timeout` arm of [`select!`] uses the current clock as well. Only the timeouts of the
+/// `assert_*!` macros always use real time, so that a test which never advances its clock fails
+/// instead of hanging.
+///
+/// [`sleep`]: fn.sleep.html
+/// [`sleep_ms`]: fn.sleep_ms.html
+/// [`Clock::enter`]: enum.Clock.html#method.enter
+/// [`spawn`]: fn.spawn.html
+/// [`spawn_promise`]: fn.spawn_promise.html
+/// [`retry`]: stages/fn.retry.html
+/// [`spawn_actor`]: fn.spawn_actor.html
+/// [`Supervisor`]: struct.Supervisor.html
+/// [`Pool`]: struct.Pool.html
+/// [`Receiver`]: ch/struct.Receiver.html
+/// [`select!`]: macro.select.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let clock = ManualClock::new();
+/// let _guard = Clock::Manual(clock.clone()).enter();
+///
+/// let (send, recv) = ch::bounded(1);
+/// let th = spawn(move || {
+///     // waits for the clock to be advanced rather than for an hour
+///     sleep(Duration::from_secs(3600));
+///     ch!(send <- "woke up");
+/// });
+///
+/// clock.wait_for_timers(1);
+/// assert_no_recv!(recv);
+/// clock.advance(Duration::from_secs(3600));
+/// assert_recv!(recv, "woke up");
+/// th.finish();
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    /// The system clock, i.e. `Instant::now()` and `std::thread::sleep`.
+    #[default]
+    Real,
+    /// A clock which only moves when it is advanced.
+    Manual(ManualClock),
+}
+
+impl Clock {
+    /// The clock of the current thread.
+    pub fn current() -> Clock {
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .unwrap_or(Clock::Real)
+    }
+
+    /// Make this the clock of the current thread until the guard is dropped.
+    pub fn enter(&self) -> ClockGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+        ClockGuard {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// The current time according to the clock.
+    pub fn now(&self) -> Instant {
+        match *self {
+            Clock::Real => Instant::now(),
+            Clock::Manual(ref clock) => clock.now(),
+        }
+    }
+
+    /// Block the current thread for `dur`.
+    pub fn sleep(&self, dur: Duration) {
+        match *self {
+            Clock::Real => thread::sleep(dur),
+            Clock::Manual(ref clock) => {
+                let _ = clock.after(dur).recv();
+            }
+        }
+    }
+
+    /// Create a channel which receives the current `Instant` once, after `dur` has elapsed.
+    ///
+    /// See [`ch::after`](ch/fn.after.html).
+    pub fn after(&self, dur: Duration) -> Receiver<Instant> {
+        match *self {
+            Clock::Real => timer::real_after(dur),
+            Clock::Manual(ref clock) => clock.after(dur),
+        }
+    }
+
+    /// Create a channel which receives the current `Instant` every `dur`.
+    ///
+    /// See [`ch::tick`](ch/fn.tick.html).
+    ///
+    /// # Panics
+    /// Panics if `dur` is zero.
+    pub fn tick(&self, dur: Duration) -> Receiver<Instant> {
+        assert!(dur != Duration::from_secs(0), "tick duration must be non-zero");
+        match *self {
+            Clock::Real => timer::real_tick(dur),
+            Clock::Manual(ref clock) => clock.schedule(dur, Some(dur)),
+        }
+    }
+
+    /// Receive a value from `recv`, waiting at most `timeout`.
+    pub fn recv_timeout<T>(
+        &self,
+        recv: &Receiver<T>,
+        timeout: Duration,
+    ) -> Result<T, RecvTimeoutError> {
+        let clock = match *self {
+            Clock::Real => return recv.recv_timeout(timeout),
+            Clock::Manual(ref clock) => clock,
+        };
+        let deadline = clock.after(timeout);
+        loop {
+            // A `Select` only notices that every case is disconnected, so check this one first.
+            match recv.try_recv() {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let mut sel = Select::new();
+            loop {
+                if let Ok(v) = sel.recv(recv) {
+                    return Ok(v);
+                }
+                if sel.recv(&deadline).is_ok() {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+                if recv.is_disconnected() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Send `value` on `send`, waiting at most `timeout` for there to be room.
+    pub fn send_timeout<T>(
+        &self,
+        send: &Sender<T>,
+        value: T,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let clock = match *self {
+            Clock::Real => return send.send_timeout(value, timeout),
+            Clock::Manual(ref clock) => clock,
+        };
+        let deadline = clock.after(timeout);
+        let mut value = value;
+        loop {
+            match send.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(v)) => return Err(SendTimeoutError::Disconnected(v)),
+                Err(TrySendError::Full(v)) => value = v,
+            }
+            let mut sel = Select::new();
+            loop {
+                value = match sel.send(send, value) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => err.into_inner(),
+                };
+                if sel.recv(&deadline).is_ok() {
+                    return Err(SendTimeoutError::Timeout(value));
+                }
+                if send.is_disconnected() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Restores the previous clock of the thread when dropped, see [`Clock::enter`].
+///
+/// [`Clock::enter`]: enum.Clock.html#method.enter
+#[must_use = "the clock is only used until the guard is dropped"]
+pub struct ClockGuard {
+    previous: Option<Clock>,
+    // The clock belongs to the thread which entered it.
+    _not_send: PhantomData<Rc<()>>,
+}
+
+impl fmt::Debug for ClockGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ClockGuard { .. }")
+    }
+}
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// A clock which only moves when [`advance`] is called, for testing.
+///
+/// Clones share the same time, so a test keeps one clone to advance and enters another with
+/// [`Clock::enter`]. Sleeps and timeouts which are due are woken by `advance` in the order of
+/// their deadlines.
+///
+/// [`advance`]: struct.ManualClock.html#method.advance
+/// [`Clock::enter`]: enum.Clock.html#method.enter
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let clock = ManualClock::new();
+/// let _guard = Clock::Manual(clock.clone()).enter();
+///
+/// let (send, recv) = ch::bounded::<u32>(1);
+/// let th = spawn(move || Clock::current().recv_timeout(&recv, Duration::from_secs(30)));
+///
+/// clock.wait_for_timers(1);
+/// clock.advance(Duration::from_secs(29));
+/// assert_eq!(1, clock.timers());
+/// clock.advance(Duration::from_secs(1));
+/// assert!(th.finish().is_err());
+/// assert_eq!(Duration::from_secs(30), clock.elapsed());
+/// drop(send);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<ManualInner>,
+}
+
+struct ManualInner {
+    start: Instant,
+    state: Mutex<ManualState>,
+    /// Notified when a timer is scheduled.
+    scheduled: Condvar,
+}
+
+struct ManualState {
+    elapsed: Duration,
+    timers: Vec<Entry>,
+}
+
+struct Entry {
+    deadline: Duration,
+    period: Option<Duration>,
+    send: Sender<Instant>,
+}
+
+impl ManualClock {
+    /// Create a clock which starts at the current time and never moves on its own.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            inner: Arc::new(ManualInner {
+                start: Instant::now(),
+                state: Mutex::new(ManualState {
+                    elapsed: Duration::from_secs(0),
+                    timers: Vec::new(),
+                }),
+                scheduled: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Move the clock forward by `dur`, firing the timers which are due.
+    ///
+    /// A `tick` fires at most once per call, since its channel only buffers a single tick.
+    pub fn advance(&self, dur: Duration) {
+        let now = {
+            let mut state = self.lock();
+            state.elapsed += dur;
+            state.elapsed
+        };
+        loop {
+            // Fire without holding the lock, the timers may be rescheduled meanwhile.
+            let entry = {
+                let mut state = self.lock();
+                let due = state
+                    .timers
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, e)| e.deadline <= now)
+                    .min_by_key(|&(_, e)| e.deadline)
+                    .map(|(i, _)| i);
+                match due {
+                    Some(i) => state.timers.swap_remove(i),
+                    None => return,
+                }
+            };
+            let mut entry = entry;
+            match entry.send.try_send(self.inner.start + entry.deadline) {
+                Err(TrySendError::Disconnected(_)) => continue,
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+            }
+            if let Some(period) = entry.period {
+                // Skip to the first tick after now. This is in nanoseconds so that a huge number
+                // of missed ticks cannot overflow, a deadline past `Duration::MAX` never fires.
+                let period = period.as_nanos();
+                let missed = (now - entry.deadline).as_nanos() / period;
+                entry.deadline = (missed + 1)
+                    .checked_mul(period)
+                    .and_then(|skip| skip.checked_add(entry.deadline.as_nanos()))
+                    .and_then(duration_from_nanos)
+                    .unwrap_or(Duration::MAX);
+                self.lock().timers.push(entry);
+            }
+        }
+    }
+
+    /// How far the clock has been advanced.
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    /// The current time of the clock.
+    pub fn now(&self) -> Instant {
+        self.inner.start + self.elapsed()
+    }
+
+    /// The number of timers waiting to fire, including the sleeps and timeouts in progress.
+    pub fn timers(&self) -> usize {
+        let mut state = self.lock();
+        state.timers.retain(|e| !e.send.is_disconnected());
+        state.timers.len()
+    }
+
+    /// Block until at least `n` timers are waiting to fire.
+    ///
+    /// This is for waiting until other threads have started to sleep before advancing the clock.
+    pub fn wait_for_timers(&self, n: usize) {
+        let mut state = self.lock();
+        loop {
+            state.timers.retain(|e| !e.send.is_disconnected());
+            if state.timers.len() >= n {
+                return;
+            }
+            state = self
+                .inner
+                .scheduled
+                .wait(state)
+                .expect("clock poisoned");
+        }
+    }
+
+    fn after(&self, dur: Duration) -> Receiver<Instant> {
+        self.schedule(dur, None)
+    }
+
+    fn schedule(&self, dur: Duration, period: Option<Duration>) -> Receiver<Instant> {
+        let (send, recv) = bounded(1);
+        let mut state = self.lock();
+        // A deadline which overflows is never reached.
+        let deadline = state.elapsed.checked_add(dur).unwrap_or(Duration::MAX);
+        if dur == Duration::from_secs(0) && period.is_none() {
+            let _ = send.try_send(self.inner.start + deadline);
+        } else {
+            state.timers.push(Entry {
+                deadline,
+                period,
+                send,
+            });
+            self.inner.scheduled.notify_all();
+        }
+        recv
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ManualState> {
+        self.inner.state.lock().expect("clock poisoned")
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ManualClock")
+            .field("elapsed", &self.elapsed())
+            .field("timers", &self.lock().timers.len())
+            .finish()
+    }
+}
+
+/// Convert nanoseconds to a `Duration`, or `None` if it is too long.
+fn duration_from_nanos(nanos: u128) -> Option<Duration> {
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    let secs = u64::try_from(nanos / NANOS_PER_SEC).ok()?;
+    Some(Duration::new(secs, (nanos % NANOS_PER_SEC) as u32))
+}
+
+/// Wrap `f` so that it runs with the clock of the current thread.
+pub(crate) fn inherit<F, T>(f: F) -> impl FnOnce() -> T
+where
+    F: FnOnce() -> T,
+{
+    let clock = CURRENT.with(|current| current.borrow().clone());
+    move || match clock {
+        Some(clock) => {
+            let _guard = clock.enter();
+            f()
+        }
+        None => f(),
+    }
+}
+
+/// Spawn a new thread, like `std::thread::spawn`.
+///
+/// The thread uses the same [`Clock`] as the current thread.
+///
+/// [`Clock`]: enum.Clock.html
+#[cfg(not(feature = "sim"))]
+pub fn spawn<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::spawn(inherit(f))
+}
+
+/// Sleep for `dur` according to the [`Clock`] of the current thread.
+///
+/// With the real clock this is `std::thread::sleep`.
+///
+/// [`Clock`]: enum.Clock.html
+#[cfg(not(feature = "sim"))]
+pub fn sleep(dur: Duration) {
+    Clock::current().sleep(dur)
+}
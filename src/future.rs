@@ -0,0 +1,478 @@
+//! Blocking bridges between channels and `std::future`.
+//!
+//! `ergo_sync` is not async, but it is often necessary to connect a pipeline of threads to code
+//! which is. This module provides:
+//!
+//! - [`RecvStream`]: created with [`ReceiverExt::into_stream`], for receiving values from a
+//!   channel inside of a future.
+//! - [`SendFuture`]: created with [`SenderExt::send_async`], a future which completes once a
+//!   value has been sent.
+//! - [`block_on`]: a minimal executor which blocks the current thread until a future completes.
+//!
+//! Operations which would block are performed on helper threads, which wake the task (through
+//! its `Waker`) once the operation is complete: one for each `RecvStream` and one shared by every
+//! pending `SendFuture`. Dropping a `RecvStream` or a pending `SendFuture` cancels its operation
+//! without losing or sending any more values.
+//!
+//! [`RecvStream`]: struct.RecvStream.html
+//! [`ReceiverExt::into_stream`]: ../ch/trait.ReceiverExt.html#tymethod.into_stream
+//! [`SendFuture`]: struct.SendFuture.html
+//! [`SenderExt::send_async`]: ../ch/trait.SenderExt.html#tymethod.send_async
+//! [`block_on`]: fn.block_on.html
+//!
+//! # Examples
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//! use ergo_sync::ch::{ReceiverExt, SenderExt};
+//!
+//! # fn main() {
+//! let (send, recv) = ch::bounded(4);
+//! spawn(move || {
+//!     for i in 0..10_u64 {
+//!         block_on(send.send_async(i)).unwrap();
+//!     }
+//! });
+//!
+//! let mut stream = recv.into_stream();
+//! let mut sum = 0;
+//! while let Some(v) = block_on(stream.recv()) {
+//!     sum += v;
+//! }
+//! assert_eq!(45, sum);
+//! # }
+//! ```
+
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Condvar, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use std_prelude::*;
+use ch::{unbounded, Receiver, Select, SendError, Sender, TryRecvError, TrySendError};
+
+/// A stream of values received from a channel, see [`ReceiverExt::into_stream`].
+///
+/// Values are only received while the stream is polled, so none are lost when it is dropped and
+/// it does not take values from clones of the `Receiver` until it needs one. When the channel is
+/// empty a helper thread waits for the next value, exiting once the channel is disconnected or
+/// the `RecvStream` is dropped.
+///
+/// [`ReceiverExt::into_stream`]: ../ch/trait.ReceiverExt.html#tymethod.into_stream
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::ch::ReceiverExt;
+///
+/// # fn main() {
+/// let (send, recv) = ch::unbounded();
+/// ch!(send <- 1);
+/// ch!(send <- 2);
+///
+/// let mut stream = recv.clone().into_stream();
+/// assert_eq!(Some(1), block_on(stream.recv()));
+///
+/// // the stream only received the value it was polled for
+/// drop(stream);
+/// assert_eq!(vec![2], recv.try_iter().collect::<Vec<_>>());
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct RecvStream<T> {
+    recv: Receiver<T>,
+    shared: Arc<StreamShared<T>>,
+    /// Dropped with the stream to wake the helper, which is started once the channel is empty.
+    cancel: Option<Sender<()>>,
+}
+
+#[derive(Debug)]
+struct StreamShared<T> {
+    state: Mutex<StreamState<T>>,
+    wanted: Condvar,
+}
+
+#[derive(Debug)]
+struct StreamState<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    /// A poll is waiting for the helper to receive a value.
+    wanted: bool,
+    closed: bool,
+    dropped: bool,
+}
+
+impl<T: Send + 'static> RecvStream<T> {
+    pub(crate) fn new(recv: Receiver<T>) -> RecvStream<T> {
+        RecvStream {
+            recv,
+            shared: Arc::new(StreamShared {
+                state: Mutex::new(StreamState {
+                    value: None,
+                    waker: None,
+                    wanted: false,
+                    closed: false,
+                    dropped: false,
+                }),
+                wanted: Condvar::new(),
+            }),
+            cancel: None,
+        }
+    }
+
+    /// Poll for the next value, returning `Ready(None)` once the channel is disconnected and
+    /// empty.
+    ///
+    /// This has the same signature as `futures::Stream::poll_next`.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let mut state = this.shared.state.lock().expect("stream poisoned");
+        if let Some(v) = state.value.take() {
+            return Poll::Ready(Some(v));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        if !state.wanted {
+            match this.recv.try_recv() {
+                Ok(v) => return Poll::Ready(Some(v)),
+                Err(TryRecvError::Disconnected) => {
+                    state.closed = true;
+                    return Poll::Ready(None);
+                }
+                Err(TryRecvError::Empty) => {
+                    state.wanted = true;
+                    this.shared.wanted.notify_one();
+                }
+            }
+        }
+        state.waker = Some(cx.waker().clone());
+        drop(state);
+        if this.cancel.is_none() {
+            this.cancel = Some(this.start_helper());
+        }
+        Poll::Pending
+    }
+
+    /// Get a future which resolves to the next value, or `None` once the channel is disconnected
+    /// and empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { stream: self }
+    }
+
+    /// Start the thread which receives a value whenever a poll is waiting for one.
+    fn start_helper(&self) -> Sender<()> {
+        let (cancel, cancelled) = unbounded::<()>();
+        let recv = self.recv.clone();
+        let bridge = self.shared.clone();
+        spawn(move || loop {
+            {
+                let mut state = bridge.state.lock().expect("stream poisoned");
+                while !state.wanted && !state.dropped {
+                    state = bridge.wanted.wait(state).expect("stream poisoned");
+                }
+                if state.dropped {
+                    return;
+                }
+            }
+
+            // Check for cancellation before every attempt, so that nothing is received once the
+            // stream is dropped.
+            let mut sel = Select::new();
+            let received = loop {
+                let _ = sel.recv(&cancelled);
+                if cancelled.is_disconnected() {
+                    return;
+                }
+                if let Ok(v) = sel.recv(&recv) {
+                    break Some(v);
+                }
+                if recv.is_disconnected() {
+                    // A value may have been sent just before the senders were dropped.
+                    break recv.try_recv().ok();
+                }
+            };
+
+            let mut state = bridge.state.lock().expect("stream poisoned");
+            if state.dropped {
+                return;
+            }
+            state.wanted = false;
+            let closed = received.is_none();
+            match received {
+                Some(v) => state.value = Some(v),
+                None => state.closed = true,
+            }
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            if closed {
+                return;
+            }
+        });
+        cancel
+    }
+}
+
+impl<T> Drop for RecvStream<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().expect("stream poisoned");
+        state.dropped = true;
+        self.shared.wanted.notify_one();
+        // `cancel` is dropped after this, waking the helper if it is receiving.
+    }
+}
+
+/// A future for the next value of a [`RecvStream`], see [`RecvStream::recv`].
+///
+/// [`RecvStream`]: struct.RecvStream.html
+/// [`RecvStream::recv`]: struct.RecvStream.html#method.recv
+#[derive(Debug)]
+pub struct Recv<'a, T: 'a> {
+    stream: &'a mut RecvStream<T>,
+}
+
+impl<'a, T: Send + 'static> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// A future which completes once a value has been sent, see [`SenderExt::send_async`].
+///
+/// The value is sent immediately if there is room in the channel. Otherwise it is handed to a
+/// single helper thread shared by every pending `SendFuture`, which sends it once there is room.
+/// Pending sends on the same channel are sent in the order they were handed over.
+///
+/// Dropping a pending `SendFuture` cancels the send: the value is dropped instead of being sent,
+/// unless the helper was already handing it over to the channel.
+///
+/// [`SenderExt::send_async`]: ../ch/trait.SenderExt.html#tymethod.send_async
+#[derive(Debug)]
+pub struct SendFuture<T> {
+    state: SendState<T>,
+}
+
+#[derive(Debug)]
+enum SendState<T> {
+    Start(Sender<T>, T),
+    /// The helper is sending, the `Sender<()>` is dropped with the future to cancel it.
+    Waiting(Arc<Mutex<SendShared<T>>>, Sender<()>),
+    Done,
+}
+
+#[derive(Debug)]
+struct SendShared<T> {
+    result: Option<Result<(), SendError<T>>>,
+    waker: Option<Waker>,
+}
+
+impl<T> SendFuture<T> {
+    pub(crate) fn new(send: Sender<T>, value: T) -> SendFuture<T> {
+        SendFuture {
+            state: SendState::Start(send, value),
+        }
+    }
+}
+
+// The value is never pinned, it is only moved into the channel.
+impl<T> Unpin for SendFuture<T> {}
+
+impl<T: Send + 'static> Future for SendFuture<T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match ::std::mem::replace(&mut self.state, SendState::Done) {
+            SendState::Start(send, value) => match send.try_send(value) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(TrySendError::Disconnected(v)) => Poll::Ready(Err(SendError(v))),
+                Err(TrySendError::Full(v)) => {
+                    let shared = Arc::new(Mutex::new(SendShared {
+                        result: None,
+                        waker: Some(cx.waker().clone()),
+                    }));
+                    let (cancel, cancelled) = unbounded::<()>();
+                    let pending = Pending {
+                        send,
+                        value: Some(v),
+                        cancelled,
+                        shared: shared.clone(),
+                    };
+                    // The helper runs for as long as the process, so this cannot fail.
+                    let _ = pending_sends().send(Box::new(pending));
+                    self.state = SendState::Waiting(shared, cancel);
+                    Poll::Pending
+                }
+            },
+            SendState::Waiting(shared, cancel) => {
+                let result = {
+                    let mut locked = shared.lock().expect("send poisoned");
+                    match locked.result.take() {
+                        Some(result) => Some(result),
+                        None => {
+                            locked.waker = Some(cx.waker().clone());
+                            None
+                        }
+                    }
+                };
+                match result {
+                    Some(result) => Poll::Ready(result),
+                    None => {
+                        self.state = SendState::Waiting(shared, cancel);
+                        Poll::Pending
+                    }
+                }
+            }
+            SendState::Done => panic!("SendFuture polled after completion"),
+        }
+    }
+}
+
+/// A send which is waiting for room in its channel, see `SendFuture`.
+trait PendingSend: Send {
+    /// The `Sender`, to tell which pending sends are on the same channel.
+    fn sender(&self) -> &dyn Any;
+
+    fn same_channel(&self, other: &dyn PendingSend) -> bool;
+
+    /// Add the cancellation of the send as a case of `sel`, returning `true` if it was cancelled.
+    fn select_cancel(&self, sel: &mut Select) -> bool;
+
+    /// Add the send as a case of `sel`, returning `true` once it has completed.
+    fn select_send(&mut self, sel: &mut Select) -> bool;
+}
+
+struct Pending<T> {
+    send: Sender<T>,
+    /// Only `None` while it is being sent.
+    value: Option<T>,
+    /// Disconnected once the future is dropped.
+    cancelled: Receiver<()>,
+    shared: Arc<Mutex<SendShared<T>>>,
+}
+
+impl<T: Send + 'static> PendingSend for Pending<T> {
+    fn sender(&self) -> &dyn Any {
+        &self.send
+    }
+
+    fn same_channel(&self, other: &dyn PendingSend) -> bool {
+        other.sender().downcast_ref::<Sender<T>>() == Some(&self.send)
+    }
+
+    fn select_cancel(&self, sel: &mut Select) -> bool {
+        let _ = sel.recv(&self.cancelled);
+        // The future was dropped, so is the value.
+        self.cancelled.is_disconnected()
+    }
+
+    fn select_send(&mut self, sel: &mut Select) -> bool {
+        let value = self.value.take().expect("pending sends have a value");
+        let result = match sel.send(&self.send, value) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let value = err.into_inner();
+                if !self.send.is_disconnected() {
+                    self.value = Some(value);
+                    return false;
+                }
+                Err(SendError(value))
+            }
+        };
+        let mut shared = self.shared.lock().expect("send poisoned");
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+}
+
+/// Get the channel which hands pending sends to the helper, starting it if this is the first use.
+fn pending_sends() -> &'static Sender<Box<dyn PendingSend>> {
+    static PENDING: OnceLock<Sender<Box<dyn PendingSend>>> = OnceLock::new();
+    PENDING.get_or_init(|| {
+        let (send, recv) = unbounded();
+        spawn(move || run_pending(recv));
+        send
+    })
+}
+
+/// Complete the pending sends, selecting over all of them at once.
+fn run_pending(handed: Receiver<Box<dyn PendingSend>>) {
+    let mut pending: Vec<Box<dyn PendingSend>> = Vec::new();
+    loop {
+        if pending.is_empty() {
+            match handed.recv() {
+                Ok(p) => pending.push(p),
+                Err(_) => return,
+            }
+        }
+        // Only the oldest send on each channel is attempted, so that every channel is a single
+        // case of the `Select` and its sends stay in order.
+        let oldest: Vec<bool> = (0..pending.len())
+            .map(|i| !pending[..i].iter().any(|p| p.same_channel(&*pending[i])))
+            .collect();
+        let mut sel = Select::new();
+        let done = 'select: loop {
+            if let Ok(p) = sel.recv(&handed) {
+                pending.push(p);
+                break None;
+            }
+            for (i, p) in pending.iter_mut().enumerate() {
+                if p.select_cancel(&mut sel) || (oldest[i] && p.select_send(&mut sel)) {
+                    break 'select Some(i);
+                }
+            }
+        };
+        if let Some(i) = done {
+            pending.remove(i);
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Block the current thread until the future completes, returning its output.
+///
+/// This is a minimal executor: the future is polled on the current thread, which parks until
+/// the future's `Waker` is woken.
+///
+/// # Examples
+/// ```rust
+/// # extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::ch::SenderExt;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(1);
+/// block_on(send.send_async("hello")).unwrap();
+/// assert_eq!("hello", recv.recv().unwrap());
+/// # }
+/// ```
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -48,15 +48,48 @@
 //!
 //! ## Types Functions and Modules
 //!
-//! - **[`ch` module]**: for channel types (also see the [`ch!`] and [`select_loop!`] macros).
+//! - **[`ch` module]**: for channel types (also see the [`ch!`], [`select!`] and [`select_loop!`]
+//!   macros). On unix this includes [`ch::signals`] for receiving signals, i.e. to shut down a
+//!   pipeline gracefully on Ctrl-C.
 //! - **[`spawn`]**: the standad `std::thread::spawn` which spawns a regular OS thread. The
 //!   advantage of this (over scoped threads) is that it can outlive the current function. The
 //!   disadvantage is that as far as the compiler knows it _always_ outlives the current function,
-//!   meaning it must own all of its variables (or they have to be `'static`).
+//!   meaning it must own all of its variables (or they have to be `'static`). The thread keeps
+//!   the [`Clock`] of the thread which spawned it.
+//! - **[`spawn_promise`]**: like `spawn` but returns a [`Promise`], whose completion can be
+//!   polled with `is_done()` or received over a channel (i.e. inside of `select_loop!`).
+//! - **[`Pool`]**: a pool of worker threads for running jobs, with either a single shared queue
+//!   or a work-stealing [`Scheduler`]. A shared pool can also scale with its queue depth. On
+//!   Linux its workers can be pinned to cores with [`PoolBuilder::pin_cores`].
+//! - **[`spawn_actor`]**: run an [`Actor`] which owns its state and handles messages sent to its
+//!   [`Addr`], with `tell` and `ask`.
+//! - **[`Progress`]**: a counter which can be cloned into each stage, with [`report`] for
+//!   printing the throughput and ETA to stderr.
+//! - **[`summary` module]**: for collecting a machine-readable (JSON) summary of a pipeline run.
+//! - **[`Supervisor`]**: runs a group of worker threads, restarting them when they panic.
+//! - **[`map_reduce`]**: for folding the values of a channel on several threads, with one
+//!   accumulator per thread.
+//! - **[`sources` module]**: sources for the first stage of a pipeline, such as [`walk_dir`]
+//!   for walking a directory in parallel.
+//! - **[`stages` module]**: reusable stages for pipelines, such as [`read_lines`] for reading the
+//!   lines of files in parallel, [`read_chunks`] for splitting large files into blocks and
+//!   [`retry`] for retrying failures with backoff. Both stages and [`Pool`]s can give each
+//!   thread its own state, see [`map_init`], [`LinesOptions::init`] and [`PoolBuilder::init`].
+//! - **[`sim` module]**: (with the `sim` feature) a deterministic scheduler for testing code
+//!   using `spawn`, `ch!` and `sleep`, which can replay a failing interleaving from its seed and
+//!   reports deadlocks instead of hanging.
+//! - **[`Clock`]**: the clock behind `sleep`, `sleep_ms`, channel timeouts and timer channels.
+//!   Tests can enter a [`ManualClock`] and advance it, so that timeouts and retries happen
+//!   instantly instead of taking real time.
+//! - **[`future` module]**: for bridging channels with `std::future`, including a minimal
+//!   [`block_on`] executor.
+//! - **[`cpu_threads`] and [`io_threads`]**: the number of threads to use for CPU and IO work,
+//!   which respect container CPU limits and can be overridden with the `ERGO_SYNC_CPU_THREADS`
+//!   and `ERGO_SYNC_IO_THREADS` environment variables.
 //! - **[`num_cpus`]**: for getting the number of cpus when creating your own thread pools.
 //! - **[`std_prelude`]**: Various concurrency related types from `std_prelude` including:
 //!   - `Atomic*`, `Mutex`, `Arc` for concurrency safe types
-//!   - `sleep` and (redefined non-deprecated) `sleep_ms`.
+//!   - `sleep` and (redefined non-deprecated) `sleep_ms`, which use the current [`Clock`].
 //!
 //! In addition it provides the following helper macros:
 //!
@@ -68,17 +101,57 @@
 //!   - `<-?` for async operation support.
 //! - **[`ch_try!`]**: to handle an expression that could be `Err` and send it over a channel if it
 //!   is.
+//! - **[`select!`]**: for selecting from multiple channels in a loop using `ch!` syntax, with
+//!   support for `default` and `timeout` arms. Closed channels are dropped from the loop.
 //! - **[`select_loop!`]**: for selecting from multiple channels.
+//! - **[`assert_recv!`], [`assert_no_recv!`], [`assert_closed!`] and [`assert_sent!`]**: for
+//!   asserting how channels behave in tests, with timeouts instead of hangs and the state of the
+//!   channel in the failure message.
 //! - **[`take!`]**: for expressing ownership consisely. You will move or clone
 //!   variables extremely often in threads, this helps you express that better than
 //!   `let value = value`.
 //!
 //! [`ch` module]: ch/index.html
+//! [`cpu_threads`]: fn.cpu_threads.html
+//! [`Clock`]: enum.Clock.html
+//! [`ManualClock`]: struct.ManualClock.html
+//! [`sim` module]: sim/index.html
+//! [`io_threads`]: fn.io_threads.html
+//! [`PoolBuilder::pin_cores`]: struct.PoolBuilder.html#method.pin_cores
+//! [`ch::signals`]: ch/fn.signals.html
 //! [`spawn`]: fn.spawn.html
+//! [`future` module]: future/index.html
+//! [`Pool`]: struct.Pool.html
+//! [`map_reduce`]: fn.map_reduce.html
+//! [`Supervisor`]: struct.Supervisor.html
+//! [`summary` module]: summary/index.html
+//! [`Progress`]: struct.Progress.html
+//! [`report`]: fn.report.html
+//! [`spawn_actor`]: fn.spawn_actor.html
+//! [`Actor`]: trait.Actor.html
+//! [`Addr`]: struct.Addr.html
+//! [`sources` module]: sources/index.html
+//! [`walk_dir`]: sources/fn.walk_dir.html
+//! [`stages` module]: stages/index.html
+//! [`read_lines`]: stages/fn.read_lines.html
+//! [`read_chunks`]: stages/fn.read_chunks.html
+//! [`retry`]: stages/fn.retry.html
+//! [`map_init`]: stages/fn.map_init.html
+//! [`LinesOptions::init`]: stages/struct.LinesOptions.html#method.init
+//! [`PoolBuilder::init`]: struct.PoolBuilder.html#method.init
+//! [`Scheduler`]: enum.Scheduler.html
+//! [`block_on`]: fn.block_on.html
+//! [`spawn_promise`]: fn.spawn_promise.html
+//! [`Promise`]: struct.Promise.html
 //! [`take!`]: macro.take.html
 //! [`ch!`]: macro.ch.html
 //! [`ch_try!`]: macro.ch_try.html
+//! [`select!`]: macro.select.html
 //! [`select_loop!`]: macro.select_loop.html
+//! [`assert_recv!`]: macro.assert_recv.html
+//! [`assert_no_recv!`]: macro.assert_no_recv.html
+//! [`assert_closed!`]: macro.assert_closed.html
+//! [`assert_sent!`]: macro.assert_sent.html
 //! [`std_prelude`]: ../std_prelude/index.html
 //!
 //! # Examples
@@ -93,9 +166,9 @@
 //!
 //! In the `ergo_sync` model you should:
 //!
-//! - Do "CPU work" by spawning up to `num_cpus::get()` threads.
+//! - Do "CPU work" by spawning up to [`cpu_threads`] threads.
 //! - Do "IO work" using between 4 - 16 threads since most storage devices only provide up to that
-//!   many channels. I personally prefer to use 8.
+//!   many channels. I personally prefer to use 8, which is the default of [`io_threads`].
 //!
 //! A typical application might look like this:
 //!
@@ -151,6 +224,11 @@
 //!
 //! [`ergo_fs`]: https://github.com/rust-crates/ergo_fs
 //!
+//! > Note: the `read_paths` function below is also provided (walking in parallel, with options for
+//! > following symlinks, maximum depth and filtering) as [`walk_dir`]. Similarly the `read_lines`
+//! > stage is provided as [`read_lines`] and the final counting stage (with one sum per thread
+//! > instead of one message per line) as [`map_reduce`].
+//!
 //! ```rust
 //! #[macro_use] extern crate ergo_sync;
 //!
@@ -238,7 +316,7 @@
 //!         let (send_count, recv_count) = ch::bounded(128);
 //!
 //!         // Create a pool of threads for actually doing the "work"
-//!         for _ in 0..num_cpus::get() {
+//!         for _ in 0..cpu_threads() {
 //!             take!(=recv_lines, =send_count);
 //!             spawn(move || {
 //!                 for line in recv_lines.iter() {
@@ -266,6 +344,9 @@ extern crate taken;
 #[allow(unused_imports)]
 #[macro_use(select_loop)]
 pub extern crate crossbeam_channel;
+extern crate crossbeam_deque;
+#[cfg(unix)]
+extern crate libc;
 pub extern crate std_prelude;
 pub extern crate num_cpus;
 
@@ -273,9 +354,13 @@ pub extern crate num_cpus;
 // Types
 pub use std_prelude::{Arc, Duration, Mutex};
 // Atomics
+#[allow(deprecated)]
 pub use std_prelude::{AtomicBool, AtomicIsize, AtomicOrdering, AtomicUsize, ATOMIC_USIZE_INIT};
 // Functions
-pub use std_prelude::{sleep, spawn};
+#[cfg(not(feature = "sim"))]
+pub use clock::{sleep, spawn};
+#[cfg(feature = "sim")]
+pub use sim::{sleep, spawn};
 
 // -------- macro exports--------
 #[allow(unused_imports)]
@@ -288,7 +373,37 @@ pub mod reexports {
 }
 pub use reexports::*;
 
+#[macro_use]
 pub mod ch;
+mod actor;
+#[cfg(target_os = "linux")]
+mod affinity;
+mod clock;
+pub mod future;
+mod pool;
+mod progress;
+mod promise;
+mod reduce;
+#[cfg(feature = "sim")]
+pub mod sim;
+mod supervisor;
+mod threads;
+pub mod sources;
+pub mod stages;
+pub mod summary;
+
+pub use actor::{spawn_actor, Actor, Addr, AskError};
+#[cfg(target_os = "linux")]
+pub use affinity::{allowed_cores, set_affinity};
+pub use clock::{Clock, ClockGuard, ManualClock};
+pub use future::block_on;
+pub use pool::{Pool, PoolBuilder, PoolHandle, ScaleEvent, Scheduler, SubmitError};
+pub use progress::{report, Progress, ProgressReporter};
+pub use promise::{spawn_promise, Promise};
+pub use reduce::{map_reduce, map_reduce_with, ReduceOptions};
+pub use supervisor::{StopToken, Strategy, Supervisor, SupervisorError, SupervisorEvent,
+                     SupervisorHandle};
+pub use threads::{cpu_threads, io_threads};
 
 use std_prelude::*;
 
@@ -319,6 +434,8 @@ where
 
 impl<T: Send + 'static> FinishHandle<T> for ::std::thread::JoinHandle<T> {
     fn finish(self) -> T {
+        #[cfg(feature = "sim")]
+        sim::wait_finished(&self);
         self.join()
             .expect("finish failed to join, thread is poisoned")
     }
@@ -326,7 +443,9 @@ impl<T: Send + 'static> FinishHandle<T> for ::std::thread::JoinHandle<T> {
 
 /// Just sleep for a certain number of milliseconds.
 ///
-/// Equivalent of `sleep(Duration::from_millis(millis))`
+/// Equivalent of `sleep(Duration::from_millis(millis))`, so it uses the current [`Clock`].
+///
+/// [`Clock`]: enum.Clock.html
 ///
 /// This function exists in `std::thread` but is deprecated, so it created here instead.
 ///
@@ -0,0 +1,1120 @@
+//! A pool of worker threads for running jobs.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic;
+use std::sync::{Condvar, RwLock};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+#[cfg(target_os = "linux")]
+use affinity::{allowed_cores, Affinity};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use std_prelude::*;
+use ch::{self, Receiver, RecvTimeoutError, Sender};
+use clock::{Clock, ClockGuard};
+use threads::cpu_threads;
+use FinishHandle;
+
+type Job<S> = Box<dyn FnOnce(&mut S) + Send + 'static>;
+
+/// How jobs are distributed to the workers of a [`Pool`].
+///
+/// [`Pool`]: struct.Pool.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheduler {
+    /// All workers receive jobs from a single shared channel.
+    ///
+    /// This is the same as spawning workers which loop over `recv.iter()` and is the best choice
+    /// for most workloads.
+    #[default]
+    Shared,
+
+    /// Each worker has its own local queue, and steals jobs from a global queue (or from other
+    /// workers) when its own queue is empty.
+    ///
+    /// Jobs submitted from inside of a worker go to that worker's local queue. This avoids
+    /// contention on a single queue when there are many workers running tiny jobs. Workers which
+    /// run out of jobs sleep until more are submitted.
+    WorkStealing,
+}
+
+/// Creates the state of each worker, see [`PoolBuilder::init`].
+///
+/// [`PoolBuilder::init`]: struct.PoolBuilder.html#method.init
+pub(crate) struct Init<S>(pub(crate) Arc<dyn Fn() -> S + Send + Sync>);
+
+impl<S> Clone for Init<S> {
+    fn clone(&self) -> Init<S> {
+        Init(self.0.clone())
+    }
+}
+
+impl<S> fmt::Debug for Init<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Init(..)")
+    }
+}
+
+/// A change in the number of workers of a scaling [`Pool`], see [`PoolBuilder::on_scale`].
+///
+/// [`Pool`]: struct.Pool.html
+/// [`PoolBuilder::on_scale`]: struct.PoolBuilder.html#method.on_scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleEvent {
+    /// A worker was added because `queued` jobs were waiting, making `threads` workers.
+    Grew {
+        /// The number of workers after growing.
+        threads: usize,
+        /// The number of jobs waiting when the pool grew.
+        queued: usize,
+    },
+    /// An idle worker exited after the keepalive, leaving `threads` workers.
+    Shrank {
+        /// The number of workers after shrinking.
+        threads: usize,
+    },
+}
+
+#[derive(Clone)]
+struct OnScale(Arc<dyn Fn(ScaleEvent) + Send + Sync>);
+
+impl fmt::Debug for OnScale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnScale(..)")
+    }
+}
+
+/// Builder for configuring a [`Pool`].
+///
+/// [`Pool`]: struct.Pool.html
+#[derive(Debug, Clone)]
+pub struct PoolBuilder<S = ()> {
+    threads: usize,
+    scheduler: Scheduler,
+    init: Init<S>,
+    max_threads: Option<usize>,
+    high_water: Option<usize>,
+    grow_after: Duration,
+    keepalive: Duration,
+    on_scale: Option<OnScale>,
+    #[cfg(target_os = "linux")]
+    cores: Option<Vec<usize>>,
+    #[cfg(target_os = "linux")]
+    pin_cores: bool,
+}
+
+impl<S: 'static> PoolBuilder<S> {
+    /// Set the number of worker threads. Defaults to [`cpu_threads`].
+    ///
+    /// [`cpu_threads`]: fn.cpu_threads.html
+    pub fn threads(mut self, threads: usize) -> PoolBuilder<S> {
+        self.threads = threads;
+        self
+    }
+
+    /// Set how jobs are distributed to the workers. Defaults to [`Scheduler::Shared`].
+    ///
+    /// [`Scheduler::Shared`]: enum.Scheduler.html#variant.Shared
+    pub fn scheduler(mut self, scheduler: Scheduler) -> PoolBuilder<S> {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Give each worker its own state, created by calling `init` on the worker's thread.
+    ///
+    /// This is for expensive resources which should only be created once per thread, such as a
+    /// compiled regex, a database connection or a scratch buffer. Jobs submitted with
+    /// [`Pool::submit_with`] receive a `&mut` reference to the state of the worker running them.
+    ///
+    /// The state is created before the worker runs its first job. If `init` panics it counts as
+    /// that job panicking, and is called again for the next job.
+    ///
+    /// [`Pool::submit_with`]: struct.Pool.html#method.submit_with
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// let (send, recv) = ch::unbounded();
+    /// let inits = Arc::new(AtomicUsize::new(0));
+    ///
+    /// let pool = {
+    ///     take!(=inits);
+    ///     Pool::builder()
+    ///         .threads(2)
+    ///         .init(move || {
+    ///             inits.fetch_add(1, AtomicOrdering::SeqCst);
+    ///             String::with_capacity(64)
+    ///         })
+    ///         .build()
+    /// };
+    ///
+    /// for i in 0..100 {
+    ///     take!(=send);
+    ///     pool.submit_with(move |buf: &mut String| {
+    ///         // reuse the worker's buffer instead of allocating a new one for every job
+    ///         buf.clear();
+    ///         buf.push_str(&i.to_string());
+    ///         ch!(send <- buf.len());
+    ///     });
+    /// }
+    /// drop(send);
+    /// pool.finish();
+    ///
+    /// assert_eq!(190, recv.iter().sum::<usize>());
+    /// assert!(inits.load(AtomicOrdering::SeqCst) <= 2);
+    /// # }
+    /// ```
+    pub fn init<T, F>(self, init: F) -> PoolBuilder<T>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        PoolBuilder {
+            threads: self.threads,
+            scheduler: self.scheduler,
+            init: Init(Arc::new(init)),
+            max_threads: self.max_threads,
+            high_water: self.high_water,
+            grow_after: self.grow_after,
+            keepalive: self.keepalive,
+            on_scale: self.on_scale,
+            #[cfg(target_os = "linux")]
+            cores: self.cores,
+            #[cfg(target_os = "linux")]
+            pin_cores: self.pin_cores,
+        }
+    }
+
+    /// Let the pool grow up to `max_threads` workers when jobs are queued faster than they are
+    /// run. The number of [`threads`] becomes the minimum.
+    ///
+    /// A worker is added once more than the [`high_water`] number of jobs have been waiting for
+    /// the [`grow_after`] period, and another one for every further period that the queue stays
+    /// above the mark. Workers above the minimum exit once they have been idle for the
+    /// [`keepalive`].
+    ///
+    /// Scaling is only supported by the [`Scheduler::Shared`] scheduler.
+    ///
+    /// [`threads`]: #method.threads
+    /// [`high_water`]: #method.high_water
+    /// [`grow_after`]: #method.grow_after
+    /// [`keepalive`]: #method.keepalive
+    /// [`Scheduler::Shared`]: enum.Scheduler.html#variant.Shared
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// let clock = ManualClock::new();
+    /// let _guard = Clock::Manual(clock.clone()).enter();
+    ///
+    /// let (send_events, recv_events) = ch::unbounded();
+    /// let pool = Pool::builder()
+    ///     .threads(1)
+    ///     .max_threads(4)
+    ///     .high_water(2)
+    ///     .grow_after(Duration::from_secs(1))
+    ///     .keepalive(Duration::from_secs(10))
+    ///     .on_scale(move |event| ch!(send_events <- event))
+    ///     .build();
+    ///
+    /// // a burst of jobs which are stuck until `send_done` is dropped
+    /// let (send_done, recv_done) = ch::bounded::<()>(0);
+    /// let submit = || {
+    ///     take!(=recv_done);
+    ///     pool.submit(move || {
+    ///         let _ = recv_done.recv();
+    ///     });
+    /// };
+    /// for _ in 0..20 {
+    ///     submit();
+    /// }
+    /// assert_eq!(1, pool.threads());
+    ///
+    /// // the pool grows by one worker for every second the queue stays above the mark
+    /// for threads in 2..5 {
+    ///     clock.advance(Duration::from_secs(1));
+    ///     submit();
+    ///     assert_eq!(threads, pool.threads());
+    /// }
+    ///
+    /// // and shrinks back down once the workers are idle for the keepalive
+    /// drop(send_done);
+    /// clock.wait_for_timers(4);
+    /// clock.advance(Duration::from_secs(10));
+    /// let events: Vec<_> = recv_events.iter().take(6).collect();
+    /// assert_eq!(ScaleEvent::Shrank { threads: 1 }, events[5]);
+    /// assert_eq!(1, pool.threads());
+    /// pool.finish();
+    /// # }
+    /// ```
+    pub fn max_threads(mut self, max_threads: usize) -> PoolBuilder<S> {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Set how many jobs must be waiting for a scaling pool to grow. Defaults to the number of
+    /// [`threads`].
+    ///
+    /// [`threads`]: #method.threads
+    pub fn high_water(mut self, high_water: usize) -> PoolBuilder<S> {
+        self.high_water = Some(high_water);
+        self
+    }
+
+    /// Set how long more than the [`high_water`] number of jobs must be waiting before a scaling
+    /// pool grows. Defaults to 100ms.
+    ///
+    /// This stops a short burst of jobs from adding workers which are only needed briefly. The
+    /// period is measured by the [`Clock`] of the thread which builds the pool.
+    ///
+    /// [`high_water`]: #method.high_water
+    /// [`Clock`]: enum.Clock.html
+    pub fn grow_after(mut self, grow_after: Duration) -> PoolBuilder<S> {
+        self.grow_after = grow_after;
+        self
+    }
+
+    /// Set how long a worker of a scaling pool can be idle before it exits. Defaults to 10
+    /// seconds.
+    ///
+    /// The keepalive is measured by the [`Clock`] of the thread which builds the pool.
+    ///
+    /// [`Clock`]: enum.Clock.html
+    pub fn keepalive(mut self, keepalive: Duration) -> PoolBuilder<S> {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Call `on_scale` whenever a scaling pool grows or shrinks, i.e. to record metrics.
+    ///
+    /// It is called on the thread which caused the change: the thread submitting the job when
+    /// growing, or the exiting worker when shrinking.
+    pub fn on_scale<F>(mut self, on_scale: F) -> PoolBuilder<S>
+    where
+        F: Fn(ScaleEvent) + Send + Sync + 'static,
+    {
+        self.on_scale = Some(OnScale(Arc::new(on_scale)));
+        self
+    }
+
+    /// Pin each worker to a single core, so that CPU bound jobs keep their caches warm.
+    ///
+    /// Workers are assigned the [`cores`] (or every core the process is allowed to run on) in
+    /// turn, so with [`cpu_threads`] threads each worker gets a distinct core. This only helps
+    /// when the pool is the main user of those cores; otherwise the scheduler can no longer move
+    /// a worker away from a busy core. Defaults to `false`.
+    ///
+    /// Pinning is best effort: a worker which cannot be pinned (i.e. because the cores allowed for
+    /// the process changed after the pool was built) runs on any of the cores instead of dying.
+    ///
+    /// Only supported on Linux.
+    ///
+    /// [`cores`]: #method.cores
+    /// [`cpu_threads`]: fn.cpu_threads.html
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// let (send, recv) = ch::unbounded();
+    /// let pool = Pool::builder().pin_cores(true).build();
+    /// for _ in 0..pool.threads() {
+    ///     take!(=send);
+    ///     pool.submit(move || ch!(send <- allowed_cores().unwrap()));
+    /// }
+    /// drop(send);
+    /// pool.finish();
+    ///
+    /// for cores in recv.iter() {
+    ///     assert_eq!(1, cores.len());
+    /// }
+    /// # }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn pin_cores(mut self, pin_cores: bool) -> PoolBuilder<S> {
+        self.pin_cores = pin_cores;
+        self
+    }
+
+    /// Restrict the workers to run on the given cores (numbered from 0).
+    ///
+    /// Without [`pin_cores`] every worker may run on any of the cores, otherwise each worker is
+    /// pinned to one of them. Defaults to every core the process is allowed to run on.
+    ///
+    /// Only supported on Linux.
+    ///
+    /// [`pin_cores`]: #method.pin_cores
+    ///
+    /// # Panics
+    /// [`build`] panics if `cores` is empty or contains a core the process is not allowed to run
+    /// on (see [`allowed_cores`]). If the allowed cores change after that, a worker which cannot
+    /// be restricted runs on any core instead of dying.
+    ///
+    /// [`build`]: #method.build
+    /// [`allowed_cores`]: fn.allowed_cores.html
+    ///
+    /// # Examples
+    /// ```rust,should_panic
+    /// # extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// // no process is allowed to run on this core
+    /// let pool = Pool::builder().cores(vec![100_000]).build();
+    /// # }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn cores(mut self, cores: Vec<usize>) -> PoolBuilder<S> {
+        self.cores = Some(cores);
+        self
+    }
+
+    /// Spawn the worker threads and return the `Pool`.
+    ///
+    /// # Panics
+    /// Panics if the number of threads is zero, or if the pool can scale and either uses the
+    /// [`Scheduler::WorkStealing`] scheduler or `max_threads` is less than `threads`. Also panics
+    /// if the [`cores`] are empty or are not all allowed for the process.
+    ///
+    /// [`Scheduler::WorkStealing`]: enum.Scheduler.html#variant.WorkStealing
+    /// [`cores`]: #method.cores
+    pub fn build(self) -> Pool<S> {
+        assert!(self.threads > 0, "a pool must have at least one thread");
+        let scaling = match self.max_threads {
+            Some(max) => {
+                assert!(
+                    self.scheduler == Scheduler::Shared,
+                    "only a pool with the shared scheduler can scale"
+                );
+                assert!(max >= self.threads, "max_threads must be at least threads");
+                Some(Scaling {
+                    min: self.threads,
+                    max,
+                    high_water: self.high_water.unwrap_or(self.threads),
+                    grow_after: self.grow_after,
+                    above_since: Mutex::new(None),
+                    keepalive: self.keepalive,
+                    on_scale: self.on_scale,
+                    workers: Mutex::new(Vec::new()),
+                })
+            }
+            None => None,
+        };
+        #[cfg(target_os = "linux")]
+        let affinity = if self.pin_cores || self.cores.is_some() {
+            if let Some(ref cores) = self.cores {
+                assert!(!cores.is_empty(), "cores must not be empty");
+                let allowed = allowed_cores().expect("failed to get the allowed cores");
+                for core in cores {
+                    assert!(
+                        allowed.contains(core),
+                        "core {} is not allowed for the process, allowed cores are {:?}",
+                        core,
+                        allowed
+                    );
+                }
+            }
+            Some(Affinity::new(self.cores, self.pin_cores))
+        } else {
+            None
+        };
+        // The deques must all exist before any worker can steal from them.
+        let mut deques = Vec::with_capacity(self.threads);
+        let queue = match self.scheduler {
+            Scheduler::Shared => {
+                deques.resize_with(self.threads, || None);
+                let (send, recv) = ch::unbounded();
+                Queue::Shared { send, recv }
+            }
+            Scheduler::WorkStealing => {
+                let mut stealers = Vec::with_capacity(self.threads);
+                for _ in 0..self.threads {
+                    let deque = Worker::new_fifo();
+                    stealers.push(deque.stealer());
+                    deques.push(Some(deque));
+                }
+                Queue::Stealing {
+                    injector: Box::new(Injector::new()),
+                    stealers,
+                    counts: (0..self.threads).map(|_| Counts::default()).collect(),
+                    external: AtomicUsize::new(0),
+                }
+            }
+        };
+        let inner = Arc::new(Inner {
+            id: NEXT_POOL_ID.fetch_add(1, AtomicOrdering::SeqCst),
+            queue,
+            init: self.init,
+            scaling,
+            clock: Clock::current(),
+            #[cfg(target_os = "linux")]
+            affinity,
+            live: AtomicUsize::new(self.threads),
+            pending: AtomicUsize::new(0),
+            panicked: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            closed: RwLock::new(false),
+            shutdown: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+            idle: Condvar::new(),
+        });
+
+        let workers = deques
+            .into_iter()
+            .enumerate()
+            .map(|(index, deque)| {
+                let inner = inner.clone();
+                spawn(move || {
+                    let _guard = inner.start_worker();
+                    match deque {
+                        Some(deque) => inner.run_stealing(index, deque),
+                        None => inner.run_shared(),
+                    }
+                })
+            })
+            .collect();
+
+        Pool { inner, workers }
+    }
+}
+
+/// A pool of worker threads which run submitted jobs.
+///
+/// The [`Scheduler`] determines how jobs are distributed to the workers. Switching between them
+/// only requires changing the [`PoolBuilder`]; the way jobs are submitted is the same.
+///
+/// Use [`finish`] to wait for all jobs (including jobs submitted by other jobs) to complete and
+/// join the worker threads. Dropping the pool does the same, except that it does not panic if a
+/// job panicked. Once the pool is being finished, only its own jobs can submit more jobs through
+/// a [`PoolHandle`].
+///
+/// The workers use the [`Clock`] of the thread which built the pool.
+///
+/// [`Scheduler`]: enum.Scheduler.html
+/// [`Clock`]: enum.Clock.html
+/// [`PoolHandle`]: struct.PoolHandle.html
+/// [`PoolBuilder`]: struct.PoolBuilder.html
+/// [`finish`]: trait.FinishHandle.html#tymethod.finish
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::unbounded();
+///
+/// let pool = Pool::builder()
+///     .threads(4)
+///     .scheduler(Scheduler::WorkStealing)
+///     .build();
+///
+/// for i in 0..10_u64 {
+///     take!(=send);
+///     let handle = pool.handle();
+///     pool.submit(move || {
+///         // jobs can submit more jobs, which go to the worker's local queue
+///         handle.submit(move || ch!(send <- i * 2)).unwrap();
+///     });
+/// }
+/// drop(send);
+/// pool.finish();
+///
+/// assert_eq!(90, recv.iter().sum::<u64>());
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Pool<S = ()> {
+    inner: Arc<Inner<S>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    /// Create a pool with `threads` workers using the [`Scheduler::Shared`] scheduler.
+    ///
+    /// [`Scheduler::Shared`]: enum.Scheduler.html#variant.Shared
+    pub fn new(threads: usize) -> Pool {
+        Pool::builder().threads(threads).build()
+    }
+
+    /// Get a builder for configuring a pool.
+    pub fn builder() -> PoolBuilder {
+        PoolBuilder {
+            threads: cpu_threads(),
+            scheduler: Scheduler::default(),
+            init: Init(Arc::new(|| ())),
+            max_threads: None,
+            high_water: None,
+            grow_after: Duration::from_millis(100),
+            keepalive: Duration::from_secs(10),
+            on_scale: None,
+            #[cfg(target_os = "linux")]
+            cores: None,
+            #[cfg(target_os = "linux")]
+            pin_cores: false,
+        }
+    }
+}
+
+impl<S: 'static> Pool<S> {
+    /// Submit a job to be run by one of the workers.
+    pub fn submit<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit_with(move |_: &mut S| job())
+    }
+
+    /// Submit a job which receives the state of the worker running it, see
+    /// [`PoolBuilder::init`].
+    ///
+    /// [`PoolBuilder::init`]: struct.PoolBuilder.html#method.init
+    pub fn submit_with<F>(&self, job: F)
+    where
+        F: FnOnce(&mut S) + Send + 'static,
+    {
+        self.inner
+            .submit(Box::new(job))
+            .expect("a pool is only closed once it is finished");
+    }
+
+    /// Get a handle for submitting jobs which can be sent to other threads (i.e. into jobs).
+    pub fn handle(&self) -> PoolHandle<S> {
+        PoolHandle {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// The current number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.inner.live.load(AtomicOrdering::SeqCst)
+    }
+}
+
+impl<S> Pool<S> {
+    /// Wait for all jobs to complete and join the workers.
+    fn shutdown(&mut self) {
+        if self.workers.is_empty() {
+            return;
+        }
+        {
+            // Jobs submitted from outside of the workers hold the read lock while they are queued,
+            // so once this is set every one of them is counted by `is_idle`.
+            *self.inner.closed.write().expect("pool poisoned") = true;
+            let mut lock = self.inner.lock.lock().expect("pool poisoned");
+            while !self.inner.is_idle() {
+                lock = self.inner.idle.wait(lock).expect("pool poisoned");
+            }
+            self.inner.shutdown.store(true, AtomicOrdering::SeqCst);
+            self.inner.wake.notify_all();
+        }
+        if let Queue::Shared { ref send, .. } = self.inner.queue {
+            for _ in 0..self.inner.live.load(AtomicOrdering::SeqCst) {
+                let _ = send.send(None);
+            }
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        if let Some(ref scaling) = self.inner.scaling {
+            let workers: Vec<_> = scaling
+                .workers
+                .lock()
+                .expect("pool poisoned")
+                .drain(..)
+                .collect();
+            for worker in workers {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl<S: 'static> FinishHandle<()> for Pool<S> {
+    /// Wait for all jobs to complete and join the worker threads.
+    ///
+    /// # Panics
+    /// Panics if any of the jobs panicked.
+    fn finish(mut self) {
+        self.shutdown();
+        let panicked = self.inner.panicked.load(AtomicOrdering::SeqCst);
+        if panicked != 0 {
+            panic!("finish failed, {} pool job(s) panicked", panicked);
+        }
+    }
+}
+
+impl<S> Drop for Pool<S> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A handle for submitting jobs to a [`Pool`], see [`Pool::handle`].
+///
+/// [`Pool`]: struct.Pool.html
+/// [`Pool::handle`]: struct.Pool.html#method.handle
+#[derive(Debug)]
+pub struct PoolHandle<S = ()> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S> Clone for PoolHandle<S> {
+    fn clone(&self) -> PoolHandle<S> {
+        PoolHandle {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: 'static> PoolHandle<S> {
+    /// Submit a job to be run by one of the workers.
+    ///
+    /// # Errors
+    /// Returns an error once the pool is being finished (or dropped), in which case the job is
+    /// dropped without being run. Jobs submitted by the pool's own jobs are always accepted, so
+    /// they can keep submitting until the pool is idle.
+    pub fn submit<F>(&self, job: F) -> Result<(), SubmitError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit_with(move |_: &mut S| job())
+    }
+
+    /// Submit a job which receives the state of the worker running it, see
+    /// [`PoolBuilder::init`].
+    ///
+    /// # Errors
+    /// Returns an error if the pool has been finished, like [`submit`].
+    ///
+    /// [`PoolBuilder::init`]: struct.PoolBuilder.html#method.init
+    /// [`submit`]: #method.submit
+    pub fn submit_with<F>(&self, job: F) -> Result<(), SubmitError>
+    where
+        F: FnOnce(&mut S) + Send + 'static,
+    {
+        self.inner.submit(Box::new(job))
+    }
+}
+
+/// The error returned by [`PoolHandle::submit`] once the pool is being finished.
+///
+/// [`PoolHandle::submit`]: struct.PoolHandle.html#method.submit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubmitError;
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("submitting a job to a pool which is finished")
+    }
+}
+
+impl Error for SubmitError {}
+
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The id of the pool the current thread is a worker of, if any.
+    static WORKER_OF: Cell<Option<usize>> = const { Cell::new(None) };
+
+    /// The current thread's place in its pool, if it is a work-stealing worker.
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+/// A work-stealing worker's pool id, index and local deque.
+///
+/// The deque is a `Worker<Job<S>>`, which is type erased since thread locals cannot be generic.
+/// The pool id determines `S`.
+struct Local {
+    pool: usize,
+    index: usize,
+    deque: Rc<dyn Any>,
+}
+
+#[derive(Debug)]
+enum Queue<S> {
+    Shared {
+        send: Sender<Option<Job<S>>>,
+        recv: Receiver<Option<Job<S>>>,
+    },
+    Stealing {
+        injector: Box<Injector<Job<S>>>,
+        stealers: Vec<Stealer<Job<S>>>,
+        /// The jobs submitted and completed by each worker.
+        counts: Box<[Counts]>,
+        /// The jobs submitted from outside of the workers.
+        external: AtomicUsize,
+    },
+}
+
+/// The jobs a work-stealing worker has submitted and completed.
+///
+/// Each worker only writes its own counts, which are on their own cache line, so running a job
+/// does not contend with the other workers. They are only summed when a worker runs out of jobs.
+#[derive(Debug, Default)]
+#[repr(align(128))]
+struct Counts {
+    submitted: AtomicUsize,
+    completed: AtomicUsize,
+}
+
+impl Counts {
+    /// Increment one of the counts of the current worker.
+    fn bump(count: &AtomicUsize) {
+        // Only this worker writes the count, so it does not need a read-modify-write.
+        count.store(count.load(AtomicOrdering::Relaxed) + 1, AtomicOrdering::Release);
+    }
+}
+
+/// The configuration and added workers of a scaling pool.
+#[derive(Debug)]
+struct Scaling {
+    min: usize,
+    max: usize,
+    high_water: usize,
+    grow_after: Duration,
+    /// When the queue went above the high-water mark, reset when a worker is added.
+    above_since: Mutex<Option<Instant>>,
+    keepalive: Duration,
+    on_scale: Option<OnScale>,
+    /// The workers added by growing, which may have exited.
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct Inner<S> {
+    id: usize,
+    queue: Queue<S>,
+    init: Init<S>,
+    scaling: Option<Scaling>,
+    /// The clock of the thread which built the pool, which the workers use and which measures
+    /// the scaling periods.
+    clock: Clock,
+    #[cfg(target_os = "linux")]
+    affinity: Option<Affinity>,
+    /// Workers which are running.
+    live: AtomicUsize,
+    /// Jobs which have been submitted to a shared queue but have not completed.
+    pending: AtomicUsize,
+    panicked: AtomicUsize,
+    /// Work-stealing workers which are (about to be) waiting on `wake`.
+    sleepers: AtomicUsize,
+    /// Set once the pool is finishing, after which only the workers can submit jobs.
+    closed: RwLock<bool>,
+    shutdown: AtomicBool,
+    lock: Mutex<()>,
+    wake: Condvar,
+    idle: Condvar,
+}
+
+impl<S> Inner<S> {
+    /// Returns `true` if every submitted job has completed.
+    fn is_idle(&self) -> bool {
+        match self.queue {
+            Queue::Shared { .. } => self.pending.load(AtomicOrdering::SeqCst) == 0,
+            Queue::Stealing {
+                ref counts,
+                ref external,
+                ..
+            } => {
+                // A job is submitted before it completes, so reading the completed counts first
+                // can only make the pool seem busy, never idle while a job is outstanding.
+                let completed: usize = counts
+                    .iter()
+                    .map(|c| c.completed.load(AtomicOrdering::Acquire))
+                    .sum();
+                let submitted: usize = counts
+                    .iter()
+                    .map(|c| c.submitted.load(AtomicOrdering::Acquire))
+                    .sum();
+                completed == submitted + external.load(AtomicOrdering::Acquire)
+            }
+        }
+    }
+}
+
+impl<S: 'static> Inner<S> {
+    fn submit(self: &Arc<Self>, job: Job<S>) -> Result<(), SubmitError> {
+        match self.queue {
+            Queue::Shared { ref send, .. } => {
+                let queue = || {
+                    self.pending.fetch_add(1, AtomicOrdering::SeqCst);
+                    send.send(Some(job)).expect("pool workers are disconnected");
+                };
+                if WORKER_OF.with(Cell::get) == Some(self.id) {
+                    queue();
+                } else {
+                    let closed = self.closed.read().expect("pool poisoned");
+                    if *closed {
+                        return Err(SubmitError);
+                    }
+                    queue();
+                }
+                if let Some(ref scaling) = self.scaling {
+                    self.scale_up(scaling, send.len());
+                }
+            }
+            Queue::Stealing {
+                ref injector,
+                ref counts,
+                ref external,
+                ..
+            } => {
+                let job = LOCAL.with(|local| match *local.borrow() {
+                    Some(ref local) if local.pool == self.id => {
+                        Counts::bump(&counts[local.index].submitted);
+                        local
+                            .deque
+                            .downcast_ref::<Worker<Job<S>>>()
+                            .expect("the pool id determines the job type")
+                            .push(job);
+                        None
+                    }
+                    _ => Some(job),
+                });
+                if let Some(job) = job {
+                    let closed = self.closed.read().expect("pool poisoned");
+                    if *closed {
+                        return Err(SubmitError);
+                    }
+                    external.fetch_add(1, AtomicOrdering::Release);
+                    injector.push(job);
+                }
+                // Pairs with the fence in `run_stealing`: either a sleeping worker sees the job
+                // before it waits, or the job sees the sleeper and wakes it.
+                atomic::fence(AtomicOrdering::SeqCst);
+                if self.sleepers.load(AtomicOrdering::SeqCst) != 0 {
+                    let _lock = self.lock.lock().expect("pool poisoned");
+                    self.wake.notify_one();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set up the current thread as a worker, before it runs any jobs.
+    ///
+    /// The returned guard keeps the pool's clock entered while the worker runs.
+    fn start_worker(&self) -> ClockGuard {
+        WORKER_OF.with(|pool| pool.set(Some(self.id)));
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref affinity) = self.affinity {
+                affinity.apply();
+            }
+        }
+        self.clock.enter()
+    }
+
+    fn run_job(&self, state: &mut Option<S>, job: Job<S>) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let state = state.get_or_insert_with(|| (self.init.0)());
+            job(state)
+        }));
+        if result.is_err() {
+            self.panicked.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+    }
+
+    /// Run a job from the shared queue, waking `shutdown` if it was the last one.
+    fn run_shared_job(&self, state: &mut Option<S>, job: Job<S>) {
+        self.run_job(state, job);
+        if self.pending.fetch_sub(1, AtomicOrdering::SeqCst) == 1 {
+            let _lock = self.lock.lock().expect("pool poisoned");
+            self.idle.notify_all();
+        }
+    }
+
+    /// Grow a scaling pool once `queued` has stayed above the high-water mark for `grow_after`.
+    ///
+    /// This is checked both when jobs are submitted and when they are received, so the pool also
+    /// grows while the workers are busy with a burst which has already been submitted.
+    fn scale_up(self: &Arc<Self>, scaling: &Scaling, queued: usize) {
+        let live = {
+            let mut above_since = scaling.above_since.lock().expect("pool poisoned");
+            if queued <= scaling.high_water {
+                *above_since = None;
+                return;
+            }
+            let now = self.clock.now();
+            let since = *above_since.get_or_insert(now);
+            if now.saturating_duration_since(since) < scaling.grow_after {
+                return;
+            }
+            // The next worker is only added if the queue stays above the mark for another period.
+            *above_since = Some(now);
+            // Counted while locked, so that no one sees the period reset without the new worker.
+            let grown = self.live
+                .fetch_update(AtomicOrdering::SeqCst, AtomicOrdering::SeqCst, |live| {
+                    if live < scaling.max {
+                        Some(live + 1)
+                    } else {
+                        None
+                    }
+                });
+            let Ok(live) = grown else {
+                return;
+            };
+            live + 1
+        };
+        self.grow(scaling, live, queued);
+    }
+
+    /// Start the worker which made `live` workers in a scaling pool.
+    fn grow(self: &Arc<Self>, scaling: &Scaling, live: usize, queued: usize) {
+        let inner = self.clone();
+        let worker = spawn(move || {
+            let _guard = inner.start_worker();
+            inner.run_shared()
+        });
+        {
+            let mut workers = scaling.workers.lock().expect("pool poisoned");
+            workers.retain(|w| !w.is_finished());
+            workers.push(worker);
+        }
+        if let Some(ref on_scale) = scaling.on_scale {
+            (on_scale.0)(ScaleEvent::Grew {
+                threads: live,
+                queued,
+            });
+        }
+    }
+
+    fn run_shared(self: &Arc<Self>) {
+        let recv = match self.queue {
+            Queue::Shared { ref recv, .. } => recv,
+            Queue::Stealing { .. } => unreachable!(),
+        };
+        let mut state = None;
+        let Some(ref scaling) = self.scaling else {
+            while let Ok(Some(job)) = recv.recv() {
+                self.run_shared_job(&mut state, job);
+            }
+            return;
+        };
+        loop {
+            match self.clock.recv_timeout(recv, scaling.keepalive) {
+                Ok(Some(job)) => {
+                    self.scale_up(scaling, recv.len());
+                    self.run_shared_job(&mut state, job);
+                }
+                Ok(None) | Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.shutdown.load(AtomicOrdering::SeqCst) {
+                        break;
+                    }
+                    let shrunk = self.live.fetch_update(
+                        AtomicOrdering::SeqCst,
+                        AtomicOrdering::SeqCst,
+                        |live| if live > scaling.min { Some(live - 1) } else { None },
+                    );
+                    if let Ok(live) = shrunk {
+                        if let Some(ref on_scale) = scaling.on_scale {
+                            (on_scale.0)(ScaleEvent::Shrank { threads: live - 1 });
+                        }
+                        return;
+                    }
+                }
+            }
+        }
+        self.live.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+
+    fn run_stealing(&self, index: usize, deque: Worker<Job<S>>) {
+        let counts = match self.queue {
+            Queue::Stealing { ref counts, .. } => &counts[index],
+            Queue::Shared { .. } => unreachable!(),
+        };
+        let deque = Rc::new(deque);
+        LOCAL.with(|local| {
+            *local.borrow_mut() = Some(Local {
+                pool: self.id,
+                index,
+                deque: deque.clone(),
+            })
+        });
+        let mut state = None;
+        loop {
+            if let Some(job) = self.find_job(index, &deque) {
+                self.run_job(&mut state, job);
+                Counts::bump(&counts.completed);
+                continue;
+            }
+
+            // Out of jobs: announce that this worker is going to sleep, then take one last look
+            // for jobs which were submitted before the announcement could be seen.
+            let lock = self.lock.lock().expect("pool poisoned");
+            self.sleepers.fetch_add(1, AtomicOrdering::SeqCst);
+            atomic::fence(AtomicOrdering::SeqCst);
+            if self.is_idle() {
+                self.idle.notify_all();
+            }
+            if self.shutdown.load(AtomicOrdering::SeqCst) {
+                self.sleepers.fetch_sub(1, AtomicOrdering::SeqCst);
+                break;
+            }
+            if !self.has_jobs() {
+                drop(self.wake.wait(lock).expect("pool poisoned"));
+            }
+            self.sleepers.fetch_sub(1, AtomicOrdering::SeqCst);
+        }
+        LOCAL.with(|local| *local.borrow_mut() = None);
+    }
+
+    /// Returns `true` if any of the work-stealing queues has a job.
+    fn has_jobs(&self) -> bool {
+        match self.queue {
+            Queue::Stealing {
+                ref injector,
+                ref stealers,
+                ..
+            } => !injector.is_empty() || stealers.iter().any(|s| !s.is_empty()),
+            Queue::Shared { .. } => unreachable!(),
+        }
+    }
+
+    /// Pop a job from the local deque, or steal one from the global queue or another worker.
+    fn find_job(&self, index: usize, deque: &Worker<Job<S>>) -> Option<Job<S>> {
+        if let Some(job) = deque.pop() {
+            return Some(job);
+        }
+        let (injector, stealers) = match self.queue {
+            Queue::Stealing {
+                ref injector,
+                ref stealers,
+                ..
+            } => (injector, stealers),
+            Queue::Shared { .. } => unreachable!(),
+        };
+        loop {
+            let mut steal = injector.steal_batch_and_pop(deque);
+            if !steal.is_success() {
+                steal = stealers
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| i != index)
+                    .map(|(_, s)| s.steal())
+                    .collect::<Steal<Job<S>>>()
+                    .or_else(|| steal);
+            }
+            match steal {
+                Steal::Success(job) => return Some(job),
+                Steal::Empty => return None,
+                Steal::Retry => {}
+            }
+        }
+    }
+}
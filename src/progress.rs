@@ -0,0 +1,250 @@
+//! Reporting the progress of a pipeline.
+
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::AtomicU64;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use std_prelude::*;
+use ch::{self, RecvTimeoutError, Sender};
+use clock::Clock;
+use FinishHandle;
+
+/// No total has been set.
+const NO_TOTAL: u64 = u64::MAX;
+
+/// A counter of how much work a pipeline has done, which can be cloned into each stage.
+///
+/// Every clone increments the same count. The progress can be printed to stderr at a fixed
+/// interval with [`report`].
+///
+/// [`report`]: fn.report.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let files = Progress::new("files").with_total(3);
+/// let lines = Progress::new("lines");
+///
+/// let reporter = report(&[&files, &lines], Duration::from_millis(100));
+/// let handles: Vec<_> = (0..3)
+///     .map(|_| {
+///         take!(=files, =lines);
+///         spawn(move || {
+///             lines.add(4000);
+///             files.inc();
+///         })
+///     })
+///     .collect();
+/// for h in handles {
+///     h.finish();
+/// }
+/// reporter.finish();
+///
+/// assert_eq!("3 / 3 files", files.to_string());
+/// assert_eq!("12,000 lines", lines.to_string());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Progress {
+    inner: Arc<ProgressInner>,
+}
+
+#[derive(Debug)]
+struct ProgressInner {
+    unit: String,
+    count: AtomicU64,
+    total: AtomicU64,
+    started: Instant,
+}
+
+impl Progress {
+    /// Create a counter of `unit`s (i.e. `"files"`) with no total.
+    pub fn new<S: Into<String>>(unit: S) -> Progress {
+        Progress {
+            inner: Arc::new(ProgressInner {
+                unit: unit.into(),
+                count: AtomicU64::new(0),
+                total: AtomicU64::new(NO_TOTAL),
+                started: Instant::now(),
+            }),
+        }
+    }
+
+    /// Set the total, so that the percentage and ETA can be reported.
+    pub fn with_total(self, total: u64) -> Progress {
+        self.set_total(total);
+        self
+    }
+
+    /// Set the total, i.e. once a directory walk has found every file.
+    pub fn set_total(&self, total: u64) {
+        self.inner.total.store(total, AtomicOrdering::Relaxed);
+    }
+
+    /// Increment the count by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increment the count by `n`.
+    pub fn add(&self, n: u64) {
+        self.inner.count.fetch_add(n, AtomicOrdering::Relaxed);
+    }
+
+    /// The current count.
+    pub fn count(&self) -> u64 {
+        self.inner.count.load(AtomicOrdering::Relaxed)
+    }
+
+    /// The total, if it has been set.
+    pub fn total(&self) -> Option<u64> {
+        match self.inner.total.load(AtomicOrdering::Relaxed) {
+            NO_TOTAL => None,
+            total => Some(total),
+        }
+    }
+
+    /// The unit being counted.
+    pub fn unit(&self) -> &str {
+        &self.inner.unit
+    }
+
+    /// The count, throughput and (if there is a total) ETA, i.e.
+    /// `12,345 / 80,000 files (3,200/s, ETA 17s)`.
+    fn status(&self) -> String {
+        let count = self.count();
+        let secs = self.inner.started.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 { count as f64 / secs } else { 0.0 };
+        let mut status = format!("{} ({}/s", self, Thousands(rate.round() as u64));
+        if let Some(total) = self.total() {
+            if rate > 0.0 && total > count {
+                let eta = Duration::from_secs(((total - count) as f64 / rate).ceil() as u64);
+                status.push_str(&format!(", ETA {}", Hms(eta)));
+            }
+        }
+        status.push(')');
+        status
+    }
+}
+
+/// Formats the count (and total) with its unit, i.e. `12,345 / 80,000 files`.
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Thousands(self.count()))?;
+        if let Some(total) = self.total() {
+            write!(f, " / {}", Thousands(total))?;
+        }
+        write!(f, " {}", self.unit())
+    }
+}
+
+/// Spawn a thread which prints the progress to stderr every `interval`.
+///
+/// When stderr is a terminal the status is rewritten in place on a single line. Otherwise (i.e.
+/// when stderr is redirected to a log file) a new line is written every interval.
+///
+/// The reporter prints the final status and stops when it is finished or dropped. The interval
+/// is measured by the current thread's [`Clock`].
+///
+/// [`Clock`]: enum.Clock.html
+pub fn report(progress: &[&Progress], interval: Duration) -> ProgressReporter {
+    let progress: Vec<Progress> = progress.iter().map(|&p| p.clone()).collect();
+    let (send_stop, recv_stop) = ch::bounded::<()>(0);
+    let clock = Clock::current();
+    let handle = spawn(move || {
+        let tty = io::stderr().is_terminal();
+        loop {
+            let stopped = !matches!(
+                clock.recv_timeout(&recv_stop, interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+            let status: Vec<_> = progress.iter().map(Progress::status).collect();
+            let status = status.join(", ");
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            // Progress is best effort, it should never stop the pipeline.
+            let _ = if !tty {
+                writeln!(stderr, "progress: {}", status)
+            } else if stopped {
+                writeln!(stderr, "\r{}\x1b[K", status)
+            } else {
+                write!(stderr, "\r{}\x1b[K", status).and_then(|_| stderr.flush())
+            };
+            if stopped {
+                return;
+            }
+        }
+    });
+    ProgressReporter {
+        stop: Some(send_stop),
+        handle: Some(handle),
+    }
+}
+
+/// The handle to a thread reporting progress, see [`report`].
+///
+/// [`report`]: fn.report.html
+#[derive(Debug)]
+pub struct ProgressReporter {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    fn stop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl FinishHandle<()> for ProgressReporter {
+    fn finish(mut self) {
+        self.stop();
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Formats a number with `,` between every thousand.
+struct Thousands(u64);
+
+impl fmt::Display for Thousands {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.0.to_string();
+        for (i, c) in digits.chars().enumerate() {
+            if i != 0 && (digits.len() - i).is_multiple_of(3) {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a duration as hours, minutes and seconds, i.e. `1h02m03s`.
+struct Hms(Duration);
+
+impl fmt::Display for Hms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        if h > 0 {
+            write!(f, "{}h{:02}m{:02}s", h, m, s)
+        } else if m > 0 {
+            write!(f, "{}m{:02}s", m, s)
+        } else {
+            write!(f, "{}s", s)
+        }
+    }
+}
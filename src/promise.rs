@@ -0,0 +1,139 @@
+//! Threads which report their completion over a channel.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle, Thread};
+
+use std_prelude::*;
+use ch::{self, Receiver};
+use {spawn, FinishHandle};
+
+/// Spawn a thread, returning a [`Promise`] which can be waited on like any other channel.
+///
+/// This is the same as [`spawn`] except the result of the thread is sent over a channel when
+/// it completes. This means that the completion of threads can be selected over, i.e. to wait for
+/// whichever thread finishes first.
+///
+/// [`Promise`]: struct.Promise.html
+/// [`spawn`]: fn.spawn.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let promises: Vec<_> = (0..10_u64)
+///     .map(|i| spawn_promise(move || {
+///         sleep_ms(10 * (10 - i));
+///         i
+///     }))
+///     .collect();
+///
+/// // wait for whichever thread finishes first
+/// let mut sel = ch::Select::new();
+/// let (index, first) = 'select: loop {
+///     for (i, promise) in promises.iter().enumerate() {
+///         if let Ok(result) = sel.recv(promise.receiver()) {
+///             break 'select (i, result.unwrap());
+///         }
+///     }
+/// };
+/// assert_eq!(9, first);
+///
+/// // the rest can be finished like a `JoinHandle`
+/// let rest: u64 = promises.into_iter()
+///     .enumerate()
+///     .filter(|&(i, _)| i != index)
+///     .map(|(_, p)| p.finish())
+///     .sum();
+/// assert_eq!(36, rest);
+/// # }
+/// ```
+pub fn spawn_promise<F, T>(f: F) -> Promise<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (send, recv) = ch::bounded(1);
+    let handle = spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let _ = send.send(result);
+    });
+    Promise { recv, handle }
+}
+
+/// A handle to a thread spawned with [`spawn_promise`].
+///
+/// The result of the thread (`Err` if it panicked) is sent on the [`receiver`] when the thread
+/// completes. It can be received directly (i.e. in [`select_loop!`]) or with [`join`] or
+/// [`finish`], but only once.
+///
+/// [`spawn_promise`]: fn.spawn_promise.html
+/// [`receiver`]: #method.receiver
+/// [`select_loop!`]: macro.select_loop.html
+/// [`join`]: #method.join
+/// [`finish`]: trait.FinishHandle.html#tymethod.finish
+#[derive(Debug)]
+pub struct Promise<T> {
+    recv: Receiver<thread::Result<T>>,
+    handle: JoinHandle<()>,
+}
+
+impl<T> Promise<T> {
+    /// The channel the result of the thread is sent on.
+    pub fn receiver(&self) -> &Receiver<thread::Result<T>> {
+        &self.recv
+    }
+
+    /// Consume the promise, returning the channel the result of the thread is sent on.
+    pub fn into_receiver(self) -> Receiver<thread::Result<T>> {
+        self.recv
+    }
+
+    /// Returns `true` if the thread has completed.
+    ///
+    /// This never blocks.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # extern crate ergo_sync;
+    /// # use ergo_sync::*;
+    /// # fn main() {
+    /// let promise = spawn_promise(|| sleep_ms(50));
+    /// assert!(!promise.is_done());
+    /// sleep_ms(200);
+    /// assert!(promise.is_done());
+    /// # }
+    /// ```
+    pub fn is_done(&self) -> bool {
+        !self.recv.is_empty() || self.recv.is_disconnected()
+    }
+
+    /// Get the underlying thread handle.
+    pub fn thread(&self) -> &Thread {
+        self.handle.thread()
+    }
+
+    /// Block until the thread completes, returning its result.
+    ///
+    /// This is the same as `JoinHandle::join()`.
+    ///
+    /// # Panics
+    /// Panics if the result was already received from the [`receiver`].
+    ///
+    /// [`receiver`]: #method.receiver
+    pub fn join(self) -> thread::Result<T> {
+        let result = self.recv
+            .recv()
+            .expect("the promise's result was already received");
+        let _ = self.handle.join();
+        result
+    }
+}
+
+impl<T: Send + 'static> FinishHandle<T> for Promise<T> {
+    fn finish(self) -> T {
+        self.join()
+            .expect("finish failed to join, thread is poisoned")
+    }
+}
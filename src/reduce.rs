@@ -0,0 +1,275 @@
+//! Reducing the values of a channel in parallel.
+
+use std::fmt;
+
+use std_prelude::*;
+use ch::{self, Receiver, Sender};
+use pool::Init;
+use stages::run_jobs;
+use {cpu_threads, FinishHandle, Pool, Scheduler};
+
+/// Options for [`map_reduce`] and [`map_reduce_with`].
+///
+/// [`map_reduce`]: fn.map_reduce.html
+/// [`map_reduce_with`]: fn.map_reduce_with.html
+pub struct ReduceOptions<S = ()> {
+    threads: usize,
+    scheduler: Scheduler,
+    init: Init<S>,
+}
+
+impl ReduceOptions {
+    /// Create the default options: fold on [`cpu_threads`] threads with the
+    /// [`Scheduler::Shared`] scheduler.
+    ///
+    /// [`cpu_threads`]: fn.cpu_threads.html
+    /// [`Scheduler::Shared`]: enum.Scheduler.html#variant.Shared
+    pub fn new() -> ReduceOptions {
+        ReduceOptions {
+            threads: cpu_threads(),
+            scheduler: Scheduler::default(),
+            init: Init(Arc::new(|| ())),
+        }
+    }
+}
+
+impl<S> ReduceOptions<S> {
+    /// Set the number of threads folding the values. Defaults to [`cpu_threads`].
+    ///
+    /// [`cpu_threads`]: fn.cpu_threads.html
+    ///
+    /// # Panics
+    /// Panics if `threads` is zero.
+    pub fn threads(mut self, threads: usize) -> ReduceOptions<S> {
+        assert!(threads > 0, "map_reduce requires at least one thread");
+        self.threads = threads;
+        self
+    }
+
+    /// Set how the values are distributed to the threads. Defaults to [`Scheduler::Shared`].
+    ///
+    /// [`Scheduler::Shared`]: enum.Scheduler.html#variant.Shared
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ReduceOptions<S> {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Give each thread its own state, created by calling `init` on the thread, which is passed
+    /// to the `fold` of [`map_reduce_with`].
+    ///
+    /// This is separate from the accumulators, which are combined once all of the values have
+    /// been folded.
+    ///
+    /// [`map_reduce_with`]: fn.map_reduce_with.html
+    pub fn init<T, F>(self, init: F) -> ReduceOptions<T>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        ReduceOptions {
+            threads: self.threads,
+            scheduler: self.scheduler,
+            init: Init(Arc::new(init)),
+        }
+    }
+}
+
+impl Default for ReduceOptions {
+    fn default() -> ReduceOptions {
+        ReduceOptions::new()
+    }
+}
+
+// Not derived, since `S` does not need to be `Clone` or `Debug`.
+impl<S> Clone for ReduceOptions<S> {
+    fn clone(&self) -> ReduceOptions<S> {
+        ReduceOptions {
+            threads: self.threads,
+            scheduler: self.scheduler,
+            init: self.init.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for ReduceOptions<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReduceOptions")
+            .field("threads", &self.threads)
+            .field("scheduler", &self.scheduler)
+            .field("init", &self.init)
+            .finish()
+    }
+}
+
+/// Fold the values received from `recv` in parallel, combining the results once all of the
+/// values have been received.
+///
+/// Each thread starts with its own accumulator from `init` and folds every value it receives
+/// into it with `fold`. Once `recv` is disconnected and empty the accumulators are combined with
+/// `combine` and the result is returned. Unlike sending every partial result over a channel,
+/// this only requires a single combine per thread, which makes it well suited for counting and
+/// grouping.
+///
+/// This blocks until all of the values have been folded.
+///
+/// # Panics
+/// Panics if any of the calls to `init` or `fold` panicked.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use std::collections::HashMap;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(128);
+/// spawn(move || {
+///     for word in "the quick brown fox jumps over the lazy dog the end".split(' ') {
+///         ch!(send <- word);
+///     }
+/// });
+///
+/// let counts = map_reduce(
+///     recv,
+///     ReduceOptions::new().threads(4),
+///     HashMap::new,
+///     |mut counts, word| {
+///         *counts.entry(word).or_insert(0) += 1;
+///         counts
+///     },
+///     |mut counts, other| {
+///         for (word, count) in other {
+///             *counts.entry(word).or_insert(0) += count;
+///         }
+///         counts
+///     },
+/// );
+/// assert_eq!(3, counts["the"]);
+/// assert_eq!(1, counts["fox"]);
+/// assert_eq!(9, counts.len());
+/// # }
+/// ```
+pub fn map_reduce<T, A, I, F, C>(
+    recv: Receiver<T>,
+    opts: ReduceOptions,
+    init: I,
+    fold: F,
+    combine: C,
+) -> A
+where
+    T: Send + 'static,
+    A: Send + 'static,
+    I: Fn() -> A + Send + Sync + 'static,
+    F: Fn(A, T) -> A + Send + Sync + 'static,
+    C: FnMut(A, A) -> A,
+{
+    map_reduce_with(recv, opts, init, move |_, acc, v| fold(acc, v), combine)
+}
+
+/// Fold the values received from `recv` in parallel like [`map_reduce`], passing the state of
+/// the thread (see [`ReduceOptions::init`]) to `fold`.
+///
+/// [`map_reduce`]: fn.map_reduce.html
+/// [`ReduceOptions::init`]: struct.ReduceOptions.html#method.init
+///
+/// # Panics
+/// Panics if any of the calls to `init` or `fold` panicked.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use std::collections::HashMap;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(128);
+/// spawn(move || {
+///     for word in "The quick fox THE lazy dog the end".split(' ') {
+///         ch!(send <- word);
+///     }
+/// });
+///
+/// // each thread reuses its own buffer for the lowercase word
+/// let counts = map_reduce_with(
+///     recv,
+///     ReduceOptions::new().threads(4).init(String::new),
+///     HashMap::new,
+///     |buf, mut counts: HashMap<String, usize>, word| {
+///         buf.clear();
+///         buf.extend(word.chars().flat_map(char::to_lowercase));
+///         match counts.get_mut(buf.as_str()) {
+///             Some(count) => *count += 1,
+///             None => {
+///                 counts.insert(buf.clone(), 1);
+///             }
+///         }
+///         counts
+///     },
+///     |mut counts, other| {
+///         for (word, count) in other {
+///             *counts.entry(word).or_insert(0) += count;
+///         }
+///         counts
+///     },
+/// );
+/// assert_eq!(3, counts["the"]);
+/// assert_eq!(6, counts.len());
+/// # }
+/// ```
+pub fn map_reduce_with<T, A, S, I, F, C>(
+    recv: Receiver<T>,
+    opts: ReduceOptions<S>,
+    init: I,
+    fold: F,
+    combine: C,
+) -> A
+where
+    T: Send + 'static,
+    A: Send + 'static,
+    S: 'static,
+    I: Fn() -> A + Send + Sync + 'static,
+    F: Fn(&mut S, A, T) -> A + Send + Sync + 'static,
+    C: FnMut(A, A) -> A,
+{
+    let init = Arc::new(init);
+    let (send_acc, recv_acc) = ch::unbounded();
+    let pool = {
+        take!(=init);
+        let state = opts.init.clone();
+        Pool::builder()
+            .threads(opts.threads)
+            .scheduler(opts.scheduler)
+            .init(move || Acc {
+                state: (state.0)(),
+                acc: Some(init()),
+                send: send_acc.clone(),
+            })
+            .build()
+    };
+    run_jobs(&pool, recv, move |worker: &mut Acc<S, A>, v| {
+        let acc = worker.acc.take().expect("the accumulator is only taken by a panicked fold");
+        worker.acc = Some(fold(&mut worker.state, acc, v));
+        true
+    });
+    // Each worker which ran a job sends its accumulator when it exits.
+    pool.finish();
+
+    recv_acc
+        .iter()
+        .reduce(combine)
+        .unwrap_or_else(|| init())
+}
+
+/// The state and accumulator of a worker, which sends the accumulator once the worker exits.
+struct Acc<S, A> {
+    state: S,
+    acc: Option<A>,
+    send: Sender<A>,
+}
+
+impl<S, A> Drop for Acc<S, A> {
+    fn drop(&mut self) {
+        if let Some(acc) = self.acc.take() {
+            let _ = self.send.send(acc);
+        }
+    }
+}
@@ -0,0 +1,580 @@
+//! A deterministic scheduler for testing code which uses [`spawn`], [`ch!`] and [`sleep`].
+//!
+//! Concurrency bugs often only show up under rare interleavings of threads. Inside of [`run`]
+//! only one thread runs at a time, and the thread which runs next is chosen by a random number
+//! generator seeded with the given seed. The following are points where another thread can be
+//! chosen:
+//!
+//! - [`spawn`]ing a thread.
+//! - Every `ch!` operation, including `ch!(send <-? value)` and `ch!(<-? recv)`.
+//! - [`sleep`] and [`sleep_ms`], which use a virtual clock. Sleeping threads wake when every
+//!   other thread is blocked, so sleeps take no real time.
+//! - [`finish`]ing a `JoinHandle`.
+//!
+//! Running the same seed always results in the same interleaving, so [`check`] can explore many
+//! interleavings and a failing seed can be replayed by setting `ERGO_SYNC_SIM_SEED`. If every
+//! thread is blocked the simulation fails with a deadlock instead of hanging.
+//!
+//! Only threads spawned with [`spawn`] inside the simulation are scheduled. Blocking in any
+//! other way (i.e. `recv.iter()`, `select!`, a `Mutex`, or the [`Pool`] and stages of this
+//! crate, which use ordinary threads) blocks the whole simulation. If no thread is scheduled for
+//! 10 seconds the simulation fails, reporting which thread was blocked.
+//!
+//! This module requires the `sim` feature, which should only be enabled for tests:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! ergo_sync = { version = "0.1", features = ["sim"] }
+//! ```
+//!
+//! [`spawn`]: fn.spawn.html
+//! [`ch!`]: ../macro.ch.html
+//! [`sleep`]: fn.sleep.html
+//! [`sleep_ms`]: ../fn.sleep_ms.html
+//! [`finish`]: ../trait.FinishHandle.html#tymethod.finish
+//! [`run`]: fn.run.html
+//! [`check`]: fn.check.html
+//! [`Pool`]: ../struct.Pool.html
+//!
+//! # Examples
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! /// Assumes that the first thread spawned sends first, which is not always true.
+//! fn racy() {
+//!     let (send_a, recv) = ch::unbounded();
+//!     let send_b = send_a.clone();
+//!     let a = spawn(move || ch!(send_a <- "a"));
+//!     let b = spawn(move || ch!(send_b <- "b"));
+//!     assert_eq!("a", ch!(<- recv));
+//!     a.finish();
+//!     b.finish();
+//! }
+//!
+//! # fn main() {
+//! let seed = (0..100)
+//!     .find(|&seed| sim::run(seed, racy).is_err())
+//!     .expect("some interleaving sends \"b\" first");
+//!
+//! // the failure can be replayed
+//! let err = sim::run(seed, racy).unwrap_err();
+//! assert_eq!(seed, err.seed());
+//! # }
+//! ```
+//!
+//! Deadlocks are reported instead of hanging:
+//!
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//!
+//! # fn main() {
+//! let err = sim::run(0, || {
+//!     let (send_ping, recv_ping) = ch::bounded::<u32>(1);
+//!     let (send_pong, recv_pong) = ch::bounded::<u32>(1);
+//!     let ponger = spawn(move || {
+//!         let v = ch!(<- recv_ping);
+//!         ch!(send_pong <- v);
+//!     });
+//!     // oops, waiting for the pong before sending the ping
+//!     let v = ch!(<- recv_pong);
+//!     ch!(send_ping <- v);
+//!     ponger.finish();
+//! }).unwrap_err();
+//!
+//! match *err.failure() {
+//!     sim::Failure::Deadlock(ref blocked) => {
+//!         assert_eq!("thread 0 blocked in `recv`, thread 1 blocked in `recv`", blocked);
+//!     }
+//!     ref failure => panic!("unexpected failure: {}", failure),
+//! }
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, MutexGuard};
+use std::thread::{self, JoinHandle, ThreadId};
+
+use std_prelude::*;
+use supervisor::panic_message;
+use clock::{self, Clock};
+
+/// The environment variable which makes [`check`] replay a single seed.
+const SEED_VAR: &str = "ERGO_SYNC_SIM_SEED";
+
+/// The number of times threads can be scheduled before the simulation fails.
+const MAX_STEPS: u64 = 1_000_000;
+
+/// How long a thread can run without being scheduled before the simulation fails.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    static CURRENT: RefCell<Option<Task>> = const { RefCell::new(None) };
+}
+
+/// Run `f` on a new thread, scheduling it and every thread it spawns deterministically using
+/// `seed`.
+///
+/// The simulation ends when `f` returns. Threads which are still running at that point are
+/// stopped by unwinding them, like they would be by the process exiting.
+///
+/// # Errors
+/// Returns an error if any thread panics, the threads deadlock, a thread blocks outside of the
+/// simulation or the threads are scheduled too many (one million) times.
+pub fn run<F>(seed: u64, f: F) -> Result<(), SimError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let sim = Arc::new(Sim {
+        state: Mutex::new(State::new(seed)),
+        cond: Condvar::new(),
+    });
+    let main = sim.start(0, f);
+
+    let mut state = sim.lock();
+    let mut steps = state.steps;
+    while state.failure.is_none() && state.tasks[0] != TaskState::Done {
+        let (s, timeout) = sim.cond
+            .wait_timeout(state, STALL_TIMEOUT)
+            .expect("sim poisoned");
+        state = s;
+        if timeout.timed_out() && state.steps == steps && state.failure.is_none()
+            && state.tasks[0] != TaskState::Done
+        {
+            let running = state.running.unwrap_or(0);
+            state.failure = Some(Failure::Stalled(format!("thread {}", running)));
+            sim.cond.notify_all();
+        }
+        steps = state.steps;
+    }
+
+    match state.failure.clone() {
+        Some(failure) => Err(SimError { seed, failure }),
+        None => {
+            drop(state);
+            let _ = main.join();
+            Ok(())
+        }
+    }
+}
+
+/// Run `f` with `runs` different seeds, panicking with the seed if any of them fail.
+///
+/// If the `ERGO_SYNC_SIM_SEED` environment variable is set only that seed is run, which is used
+/// to replay a failure.
+///
+/// # Panics
+/// Panics if a seed fails, or if `ERGO_SYNC_SIM_SEED` is not an integer.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// sim::check(100, || {
+///     let (send, recv) = ch::bounded(1);
+///     let producer = spawn(move || {
+///         for i in 0..3 {
+///             ch!(send <- i);
+///             sleep_ms(1000);
+///         }
+///     });
+///     let mut values = Vec::new();
+///     for _ in 0..3 {
+///         values.push(ch!(<- recv));
+///     }
+///     ch!(! <- recv);
+///     producer.finish();
+///     assert_eq!(vec![0, 1, 2], values);
+/// });
+/// # }
+/// ```
+pub fn check<F>(runs: u64, f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let seeds = match env::var(SEED_VAR) {
+        Ok(seed) => {
+            let seed: u64 = seed.trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be an integer, got {:?}", SEED_VAR, seed));
+            seed..seed + 1
+        }
+        Err(_) => 0..runs,
+    };
+    for seed in seeds {
+        let f = f.clone();
+        if let Err(err) = run(seed, move || f()) {
+            panic!("{} (replay it with {}={})", err, SEED_VAR, seed);
+        }
+    }
+}
+
+/// Spawn a thread, which is scheduled by the simulation if called inside of one.
+///
+/// Outside of a simulation this is `std::thread::spawn`. Either way the thread uses the same
+/// [`Clock`] as the current thread.
+///
+/// [`Clock`]: ../enum.Clock.html
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let f = clock::inherit(f);
+    let Some(task) = current() else {
+        return thread::spawn(f);
+    };
+    let id = {
+        let mut state = task.sim.lock();
+        state.tasks.push(TaskState::Runnable);
+        state.tasks.len() - 1
+    };
+    let handle = task.sim.start(id, f);
+    task.yield_now();
+    handle
+}
+
+/// Sleep for `dur`, using the virtual clock if called inside of a simulation.
+///
+/// Outside of a simulation this sleeps according to the current thread's [`Clock`].
+///
+/// [`Clock`]: ../enum.Clock.html
+pub fn sleep(dur: Duration) {
+    match current() {
+        Some(task) => task.switch(|state| TaskState::Sleeping(state.now + dur)),
+        None => Clock::current().sleep(dur),
+    }
+}
+
+/// Wait for the thread of `handle` to finish, if it is part of the current simulation.
+pub(crate) fn wait_finished<T>(handle: &JoinHandle<T>) {
+    let Some(task) = current() else {
+        return;
+    };
+    let id = match task.sim.lock().threads.get(&handle.thread().id()) {
+        Some(&id) => id,
+        None => return,
+    };
+    task.block_on("finish", || {
+        if task.sim.lock().tasks[id] == TaskState::Done {
+            Some(())
+        } else {
+            None
+        }
+    });
+}
+
+/// The simulated thread currently running, if any.
+///
+/// Threads which are unwinding are no longer scheduled, so that stopping them cannot panic again.
+pub(crate) fn current() -> Option<Task> {
+    if thread::panicking() {
+        return None;
+    }
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// The error returned by [`run`] when a simulation fails.
+///
+/// [`run`]: fn.run.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimError {
+    seed: u64,
+    failure: Failure,
+}
+
+impl SimError {
+    /// The seed of the simulation, which can be used to replay it.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Why the simulation failed.
+    pub fn failure(&self) -> &Failure {
+        &self.failure
+    }
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "simulation with seed {} failed: {}", self.seed, self.failure)
+    }
+}
+
+impl Error for SimError {}
+
+/// Why a simulation failed, see [`SimError`].
+///
+/// [`SimError`]: struct.SimError.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// A thread panicked, with the thread and the panic message.
+    Panicked(String),
+    /// Every thread was blocked, with the operation each was blocked in, i.e.
+    /// ``thread 0 blocked in `recv`, thread 1 blocked in `send` ``.
+    Deadlock(String),
+    /// The thread blocked outside of the simulation, i.e. in `recv.iter()`.
+    Stalled(String),
+    /// The threads were scheduled too many times, i.e. because they are spinning.
+    StepLimit,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Panicked(ref message) => f.write_str(message),
+            Failure::Deadlock(ref blocked) => write!(f, "deadlock: {}", blocked),
+            Failure::Stalled(ref thread) => {
+                write!(f, "{} blocked outside of the simulation", thread)
+            }
+            Failure::StepLimit => write!(f, "threads were scheduled over {} times", MAX_STEPS),
+        }
+    }
+}
+
+/// The payload used to unwind threads once the simulation has ended.
+struct Aborted;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskState {
+    Runnable,
+    /// Blocked in `op`, which will be retried once a thread has run since `epoch`.
+    Blocked { epoch: u64, op: &'static str },
+    /// Sleeping until the virtual clock reaches the time.
+    Sleeping(Duration),
+    Done,
+}
+
+struct Sim {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+struct State {
+    rng: u64,
+    tasks: Vec<TaskState>,
+    /// The only task which is allowed to run.
+    running: Option<usize>,
+    /// Incremented whenever a task runs code which could unblock another task.
+    epoch: u64,
+    /// The virtual clock.
+    now: Duration,
+    steps: u64,
+    /// The main task has returned.
+    stopping: bool,
+    failure: Option<Failure>,
+    threads: HashMap<ThreadId, usize>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Task {
+    sim: Arc<Sim>,
+    id: usize,
+}
+
+impl Sim {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("sim poisoned")
+    }
+
+    /// Spawn the thread for task `id`, which waits until it is scheduled before running `f`.
+    fn start<F, T>(self: &Arc<Self>, id: usize, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let task = Task {
+            sim: self.clone(),
+            id,
+        };
+        let handle = thread::spawn(move || {
+            CURRENT.with(|current| *current.borrow_mut() = Some(task.clone()));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                task.sim.wait_turn(task.sim.lock(), task.id);
+                f()
+            }));
+            CURRENT.with(|current| *current.borrow_mut() = None);
+            task.exit(&result);
+            match result {
+                Ok(v) => v,
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        });
+        self.lock().threads.insert(handle.thread().id(), id);
+        handle
+    }
+
+    /// Block until task `id` is scheduled, unwinding if the simulation has ended.
+    fn wait_turn(&self, mut state: MutexGuard<'_, State>, id: usize) {
+        loop {
+            if state.failure.is_some() || state.stopping {
+                drop(state);
+                panic::resume_unwind(Box::new(Aborted));
+            }
+            if state.running == Some(id) {
+                return;
+            }
+            state = self.cond.wait(state).expect("sim poisoned");
+        }
+    }
+}
+
+impl Task {
+    /// Let the scheduler choose the next task to run.
+    pub(crate) fn yield_now(&self) {
+        self.switch(|_| TaskState::Runnable);
+    }
+
+    /// Perform a blocking operation by calling `attempt` until it returns `Some`, letting other
+    /// tasks run in between.
+    pub(crate) fn block_on<R, F>(&self, op: &'static str, mut attempt: F) -> R
+    where
+        F: FnMut() -> Option<R>,
+    {
+        self.yield_now();
+        loop {
+            if let Some(result) = attempt() {
+                self.sim.lock().epoch += 1;
+                return result;
+            }
+            self.switch(|state| TaskState::Blocked {
+                epoch: state.epoch,
+                op,
+            });
+        }
+    }
+
+    /// Change the state of this task and wait until it is scheduled again.
+    fn switch<F>(&self, next: F)
+    where
+        F: FnOnce(&State) -> TaskState,
+    {
+        let mut state = self.sim.lock();
+        state.tasks[self.id] = next(&state);
+        state.schedule();
+        self.sim.cond.notify_all();
+        self.sim.wait_turn(state, self.id);
+    }
+
+    fn exit<T>(&self, result: &thread::Result<T>) {
+        let mut state = self.sim.lock();
+        state.tasks[self.id] = TaskState::Done;
+        if let Err(ref payload) = *result {
+            if !payload.is::<Aborted>() && state.failure.is_none() {
+                state.failure = Some(Failure::Panicked(format!(
+                    "thread {} panicked: {}",
+                    self.id,
+                    panic_message(&**payload)
+                )));
+            }
+        }
+        if self.id == 0 {
+            state.stopping = true;
+            state.running = None;
+        } else if state.running == Some(self.id) {
+            state.schedule();
+        }
+        self.sim.cond.notify_all();
+    }
+}
+
+impl State {
+    fn new(seed: u64) -> State {
+        State {
+            // Mix the seed so that nearby seeds give unrelated interleavings, keeping it nonzero.
+            rng: (seed ^ 0x9E37_79B9_7F4A_7C15).wrapping_mul(0xBF58_476D_1CE4_E5B9) | 1,
+            tasks: vec![TaskState::Runnable],
+            running: Some(0),
+            epoch: 0,
+            now: Duration::from_secs(0),
+            steps: 0,
+            stopping: false,
+            failure: None,
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Choose the next task to run, advancing the clock if every task is blocked or sleeping.
+    fn schedule(&mut self) {
+        self.running = None;
+        if self.stopping || self.failure.is_some() {
+            return;
+        }
+        loop {
+            let (now, epoch) = (self.now, self.epoch);
+            for task in &mut self.tasks {
+                if let TaskState::Sleeping(wake) = *task {
+                    if wake <= now {
+                        *task = TaskState::Runnable;
+                    }
+                }
+            }
+            let ready: Vec<usize> = (0..self.tasks.len())
+                .filter(|&id| match self.tasks[id] {
+                    TaskState::Runnable => true,
+                    TaskState::Blocked { epoch: e, .. } => e < epoch,
+                    TaskState::Sleeping(_) | TaskState::Done => false,
+                })
+                .collect();
+
+            if !ready.is_empty() {
+                self.steps += 1;
+                if self.steps > MAX_STEPS {
+                    self.failure = Some(Failure::StepLimit);
+                    return;
+                }
+                let id = ready[(self.next_random() % ready.len() as u64) as usize];
+                if self.tasks[id] == TaskState::Runnable {
+                    // The task will run code which could unblock the others.
+                    self.epoch += 1;
+                }
+                self.running = Some(id);
+                return;
+            }
+
+            let wake = self.tasks
+                .iter()
+                .filter_map(|task| match *task {
+                    TaskState::Sleeping(wake) => Some(wake),
+                    _ => None,
+                })
+                .min();
+            match wake {
+                Some(wake) => self.now = wake,
+                None => {
+                    let blocked: Vec<_> = self.tasks
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(id, task)| match *task {
+                            TaskState::Blocked { op, .. } => {
+                                Some(format!("thread {} blocked in `{}`", id, op))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    self.failure = Some(Failure::Deadlock(blocked.join(", ")));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// xorshift64*
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
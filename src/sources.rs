@@ -0,0 +1,259 @@
+//! Sources which produce values for the first stage of a pipeline.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use std_prelude::*;
+use ch::{self, Receiver, Sender};
+use clock::inherit;
+use {io_threads, FinishHandle, Pool, PoolHandle, Scheduler};
+
+/// Options for [`walk_dir`].
+///
+/// [`walk_dir`]: fn.walk_dir.html
+#[derive(Clone)]
+pub struct WalkOptions {
+    threads: usize,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    filter: Option<Filter>,
+}
+
+type Filter = Arc<dyn Fn(&Path) -> bool + Send + Sync>;
+
+impl WalkOptions {
+    /// Create the default options: walk with [`io_threads`] threads, ignore symlinks, no maximum
+    /// depth and no filter.
+    ///
+    /// [`io_threads`]: ../fn.io_threads.html
+    pub fn new() -> WalkOptions {
+        WalkOptions {
+            threads: io_threads(),
+            follow_links: false,
+            max_depth: None,
+            filter: None,
+        }
+    }
+
+    /// Set the number of threads to walk with. Defaults to [`io_threads`].
+    ///
+    /// [`io_threads`]: ../fn.io_threads.html
+    pub fn threads(mut self, threads: usize) -> WalkOptions {
+        self.threads = threads;
+        self
+    }
+
+    /// Follow symlinks to files and directories. Defaults to `false`, in which case symlinks are
+    /// ignored.
+    ///
+    /// When following symlinks, a symlink which points to one of its own parent directories is
+    /// reported as an error instead of being walked forever.
+    pub fn follow_links(mut self, follow_links: bool) -> WalkOptions {
+        self.follow_links = follow_links;
+        self
+    }
+
+    /// Only walk `depth` levels below the root, i.e. a depth of 1 only sends the files directly
+    /// inside of the root. Defaults to no limit.
+    pub fn max_depth(mut self, depth: usize) -> WalkOptions {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Only send the files for which `filter` returns `true`.
+    pub fn filter<F>(mut self, filter: F) -> WalkOptions
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+}
+
+impl Default for WalkOptions {
+    fn default() -> WalkOptions {
+        WalkOptions::new()
+    }
+}
+
+impl fmt::Debug for WalkOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WalkOptions")
+            .field("threads", &self.threads)
+            .field("follow_links", &self.follow_links)
+            .field("max_depth", &self.max_depth)
+            .field("filter", &self.filter.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Recursively walk the directory at `root` in parallel, sending the path of every file found.
+///
+/// Any `io::Error` hit while walking is sent on the second channel and the walk continues.
+/// Both channels are disconnected once the walk is complete.
+///
+/// The paths channel is bounded, so the walk will wait for the paths to be received. The
+/// errors channel is not, so it does not need to be received from until the walk is done. The
+/// walk stops early if the paths `Receiver` is dropped.
+///
+/// # Examples
+/// ```rust
+/// extern crate ergo_sync;
+/// use std::path::PathBuf;
+/// use ergo_sync::sources::{walk_dir, WalkOptions};
+///
+/// # fn main() {
+/// let opts = WalkOptions::new()
+///     .max_depth(2)
+///     .filter(|path| path.extension().map_or(false, |e| e == "rs"));
+/// let (recv_paths, recv_errs) = walk_dir("src", opts);
+///
+/// let paths: Vec<_> = recv_paths.iter().collect();
+/// assert!(paths.contains(&PathBuf::from("src/lib.rs")));
+/// assert!(paths.contains(&PathBuf::from("src/ch/mod.rs")));
+///
+/// assert_eq!(0, recv_errs.iter().count());
+/// # }
+/// ```
+pub fn walk_dir<P: AsRef<Path>>(
+    root: P,
+    opts: WalkOptions,
+) -> (Receiver<PathBuf>, Receiver<io::Error>) {
+    let (send_paths, recv_paths) = ch::bounded(128);
+    let (send_errs, recv_errs) = ch::unbounded();
+    let root = root.as_ref().to_path_buf();
+    spawn(inherit(move || {
+        let pool = Pool::builder()
+            .threads(opts.threads)
+            .scheduler(Scheduler::WorkStealing)
+            .build();
+        let walker = Walker {
+            opts,
+            pool: pool.handle(),
+            send_paths,
+            send_errs,
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let ancestors = Arc::new(Vec::new());
+        match fs::metadata(&root) {
+            Ok(ref meta) if meta.is_dir() => walker.walk(root, 0, ancestors),
+            Ok(_) => {
+                walker.send_file(root);
+            }
+            Err(err) => walker.send_err(err),
+        }
+        drop(walker);
+        pool.finish();
+    }));
+    (recv_paths, recv_errs)
+}
+
+/// The state shared by each job of the walk.
+#[derive(Clone)]
+struct Walker {
+    opts: WalkOptions,
+    pool: PoolHandle,
+    send_paths: Sender<PathBuf>,
+    send_errs: Sender<io::Error>,
+    /// Set once the paths receiver is dropped, so that the rest of the walk is skipped.
+    stopped: Arc<AtomicBool>,
+}
+
+impl Walker {
+    /// Submit a job to list the directory at `dir`.
+    fn walk(&self, dir: PathBuf, depth: usize, ancestors: Arc<Vec<PathBuf>>) {
+        if self.stopped.load(AtomicOrdering::SeqCst) {
+            return;
+        }
+        let walker = self.clone();
+        self.pool
+            .submit(move || walker.read_dir(&dir, depth, &ancestors))
+            .expect("the pool is finished after the walk");
+    }
+
+    fn read_dir(&self, dir: &Path, depth: usize, ancestors: &Arc<Vec<PathBuf>>) {
+        if self.stopped.load(AtomicOrdering::SeqCst)
+            || self.opts.max_depth.is_some_and(|max| depth >= max)
+        {
+            return;
+        }
+        let ancestors = if self.opts.follow_links {
+            let Some(canonical) = self.ok(dir.canonicalize()) else {
+                return;
+            };
+            if ancestors.contains(&canonical) {
+                self.send_err(io::Error::other(format!(
+                    "filesystem loop found at {}",
+                    dir.display()
+                )));
+                return;
+            }
+            let mut ancestors = (**ancestors).clone();
+            ancestors.push(canonical);
+            Arc::new(ancestors)
+        } else {
+            ancestors.clone()
+        };
+
+        let depth = depth + 1;
+        let Some(entries) = self.ok(fs::read_dir(dir)) else {
+            return;
+        };
+        for entry in entries {
+            let Some(entry) = self.ok(entry) else {
+                continue;
+            };
+            let Some(mut file_type) = self.ok(entry.file_type()) else {
+                continue;
+            };
+            if file_type.is_symlink() {
+                if !self.opts.follow_links {
+                    continue;
+                }
+                let Some(meta) = self.ok(fs::metadata(entry.path())) else {
+                    continue;
+                };
+                file_type = meta.file_type();
+            }
+
+            if file_type.is_file() {
+                if !self.send_file(entry.path()) {
+                    return;
+                }
+            } else if file_type.is_dir() && self.opts.max_depth.is_none_or(|max| depth < max) {
+                self.walk(entry.path(), depth, ancestors.clone());
+            }
+        }
+    }
+
+    /// Send the path if it passes the filter, returning `false` if the walk should stop.
+    fn send_file(&self, path: PathBuf) -> bool {
+        let keep = match self.opts.filter {
+            Some(ref filter) => filter(&path),
+            None => true,
+        };
+        if keep && self.send_paths.send(path).is_err() {
+            // The receiver was dropped, so the caller is no longer interested.
+            self.stopped.store(true, AtomicOrdering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn send_err(&self, err: io::Error) {
+        // The receiver may be dropped if the caller is not interested in errors.
+        let _ = self.send_errs.send(err);
+    }
+
+    /// Send the error (if any), returning the value if there was no error.
+    fn ok<T>(&self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(v) => Some(v),
+            Err(err) => {
+                self.send_err(err);
+                None
+            }
+        }
+    }
+}
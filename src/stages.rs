@@ -0,0 +1,1035 @@
+//! Reusable stages for building pipelines.
+//!
+//! Each stage receives values from a `Receiver`, processes them as jobs on its own [`Pool`] and
+//! returns a `Receiver` for its output. The output channel is disconnected once the input channel
+//! is disconnected and all of the values have been processed, so stages can be chained together
+//! like iterators.
+//!
+//! Every stage's options have a `scheduler`, so a stage can switch to the
+//! [`Scheduler::WorkStealing`] scheduler with a single change.
+//!
+//! [`Pool`]: ../struct.Pool.html
+//! [`Scheduler::WorkStealing`]: ../enum.Scheduler.html#variant.WorkStealing
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+
+use std_prelude::*;
+use ch::{self, Receiver, Sender};
+use clock::{inherit, Clock};
+use pool::Init;
+use {cpu_threads, io_threads, FinishHandle, Pool, Scheduler};
+
+/// What to do when a line is not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncodingErrors {
+    /// Send an `io::Error` (with kind `InvalidData`) and stop reading the file.
+    ///
+    /// This is the same as the behavior of `BufRead::lines` with `ch_try!(errs, line, return)`.
+    #[default]
+    Error,
+    /// Skip the line, continuing with the rest of the file.
+    Skip,
+    /// Replace invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy,
+}
+
+/// Options for [`read_lines`] and [`read_lines_with`].
+///
+/// [`read_lines`]: fn.read_lines.html
+/// [`read_lines_with`]: fn.read_lines_with.html
+pub struct LinesOptions<S = ()> {
+    threads: usize,
+    buf_size: usize,
+    capacity: usize,
+    encoding: EncodingErrors,
+    scheduler: Scheduler,
+    init: Init<S>,
+}
+
+impl LinesOptions {
+    /// Create the default options: read with [`io_threads`] threads, an 8 KiB read buffer per
+    /// file, an output channel holding 128 lines, [`EncodingErrors::Error`] and the
+    /// [`Scheduler::Shared`] scheduler.
+    ///
+    /// [`io_threads`]: ../fn.io_threads.html
+    /// [`EncodingErrors::Error`]: enum.EncodingErrors.html#variant.Error
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn new() -> LinesOptions {
+        LinesOptions {
+            threads: io_threads(),
+            buf_size: 8 * 1024,
+            capacity: 128,
+            encoding: EncodingErrors::default(),
+            scheduler: Scheduler::default(),
+            init: Init(Arc::new(|| ())),
+        }
+    }
+}
+
+impl<S> LinesOptions<S> {
+    /// Set the number of threads reading files. Defaults to [`io_threads`].
+    ///
+    /// [`io_threads`]: ../fn.io_threads.html
+    ///
+    /// # Panics
+    /// Panics if `threads` is zero.
+    pub fn threads(mut self, threads: usize) -> LinesOptions<S> {
+        assert!(threads > 0, "threads must be non-zero");
+        self.threads = threads;
+        self
+    }
+
+    /// Set the size (in bytes) of the buffer used to read each file. Defaults to 8 KiB.
+    pub fn buf_size(mut self, buf_size: usize) -> LinesOptions<S> {
+        self.buf_size = buf_size;
+        self
+    }
+
+    /// Set the number of lines the output channel can hold. Defaults to 128.
+    pub fn capacity(mut self, capacity: usize) -> LinesOptions<S> {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set what to do when a line is not valid UTF-8. Defaults to [`EncodingErrors::Error`].
+    ///
+    /// [`EncodingErrors::Error`]: enum.EncodingErrors.html#variant.Error
+    pub fn encoding(mut self, encoding: EncodingErrors) -> LinesOptions<S> {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Set how the files are distributed to the threads. Defaults to [`Scheduler::Shared`].
+    ///
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn scheduler(mut self, scheduler: Scheduler) -> LinesOptions<S> {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Give each thread its own state, created by calling `init` on the thread, which is passed
+    /// to the function of [`read_lines_with`].
+    ///
+    /// [`read_lines_with`]: fn.read_lines_with.html
+    pub fn init<T, F>(self, init: F) -> LinesOptions<T>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        LinesOptions {
+            threads: self.threads,
+            buf_size: self.buf_size,
+            capacity: self.capacity,
+            encoding: self.encoding,
+            scheduler: self.scheduler,
+            init: Init(Arc::new(init)),
+        }
+    }
+}
+
+impl Default for LinesOptions {
+    fn default() -> LinesOptions {
+        LinesOptions::new()
+    }
+}
+
+// Not derived, since `S` does not need to be `Clone` or `Debug`.
+impl<S> Clone for LinesOptions<S> {
+    fn clone(&self) -> LinesOptions<S> {
+        LinesOptions {
+            threads: self.threads,
+            buf_size: self.buf_size,
+            capacity: self.capacity,
+            encoding: self.encoding,
+            scheduler: self.scheduler,
+            init: self.init.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for LinesOptions<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LinesOptions")
+            .field("threads", &self.threads)
+            .field("buf_size", &self.buf_size)
+            .field("capacity", &self.capacity)
+            .field("encoding", &self.encoding)
+            .field("scheduler", &self.scheduler)
+            .field("init", &self.init)
+            .finish()
+    }
+}
+
+/// Read the lines of each file received from `recv_paths` in parallel.
+///
+/// Each line is sent with the path of its file and its line number (starting at 1). The line
+/// does not include the trailing `\n` or `\r\n`.
+///
+/// Any `io::Error` is sent on `errs` (in the same way as [`ch_try!`]) and the rest of that file
+/// is skipped.
+///
+/// [`ch_try!`]: ../macro.ch_try.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::sources::{walk_dir, WalkOptions};
+/// use ergo_sync::stages::{read_lines, LinesOptions};
+///
+/// # fn main() {
+/// let (recv_paths, recv_walk_errs) = walk_dir("src", WalkOptions::new());
+/// let (send_errs, recv_errs) = ch::unbounded();
+/// let recv_lines = read_lines(recv_paths, send_errs, LinesOptions::new().threads(4));
+///
+/// let mut found = false;
+/// for (path, line_num, line) in recv_lines.iter() {
+///     if line.contains("ergonomic, therefore fun") && path.ends_with("lib.rs") {
+///         assert_eq!(1, line_num);
+///         found = true;
+///     }
+/// }
+/// assert!(found);
+/// assert_eq!(0, recv_walk_errs.iter().count());
+/// assert_eq!(0, recv_errs.iter().count());
+/// # }
+/// ```
+pub fn read_lines(
+    recv_paths: Receiver<PathBuf>,
+    errs: Sender<io::Error>,
+    opts: LinesOptions,
+) -> Receiver<(Arc<PathBuf>, usize, String)> {
+    read_lines_with(recv_paths, errs, opts, |_, path, line_num, line| {
+        Some((path.clone(), line_num, line))
+    })
+}
+
+/// Read the lines of each file received from `recv_paths` in parallel, like [`read_lines`], and
+/// run `f` on each line on the thread which read it.
+///
+/// `f` receives the state of its thread (see [`LinesOptions::init`]), the path of the file, the
+/// line number and the line. Only the values for which `f` returns `Some` are sent, so this can
+/// filter and parse the lines without a separate stage.
+///
+/// [`read_lines`]: fn.read_lines.html
+/// [`LinesOptions::init`]: struct.LinesOptions.html#method.init
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::sources::{walk_dir, WalkOptions};
+/// use ergo_sync::stages::{read_lines_with, LinesOptions};
+///
+/// # fn main() {
+/// let (recv_paths, _) = walk_dir("src", WalkOptions::new());
+/// let (send_errs, recv_errs) = ch::unbounded();
+/// // each thread reuses its own buffer for the lowercase line
+/// let opts = LinesOptions::new().threads(4).init(String::new);
+/// let recv_found = read_lines_with(recv_paths, send_errs, opts, |buf, path, _, line| {
+///     buf.clear();
+///     buf.extend(line.chars().flat_map(char::to_lowercase));
+///     if buf.contains("ergonomic, therefore fun") && path.ends_with("lib.rs") {
+///         Some(line)
+///     } else {
+///         None
+///     }
+/// });
+///
+/// assert_eq!(1, recv_found.iter().count());
+/// assert_eq!(0, recv_errs.iter().count());
+/// # }
+/// ```
+pub fn read_lines_with<S, U, F>(
+    recv_paths: Receiver<PathBuf>,
+    errs: Sender<io::Error>,
+    opts: LinesOptions<S>,
+    f: F,
+) -> Receiver<U>
+where
+    S: 'static,
+    U: Send + 'static,
+    F: Fn(&mut S, &Arc<PathBuf>, usize, String) -> Option<U> + Send + Sync + 'static,
+{
+    let (send_lines, recv_lines) = ch::bounded(opts.capacity);
+    spawn(inherit(move || {
+        let init = opts.init.clone();
+        let pool = Pool::builder()
+            .threads(opts.threads)
+            .scheduler(opts.scheduler)
+            .init(move || (init.0)())
+            .build();
+        run_jobs(&pool, recv_paths, move |state, path| {
+            read_file_lines(path, &errs, &opts, |path, line_num, line| {
+                match f(state, path, line_num, line) {
+                    Some(out) => send_lines.send(out).is_ok(),
+                    None => true,
+                }
+            })
+        });
+        pool.finish();
+    }));
+    recv_lines
+}
+
+/// Pass each line of a single file to `emit`, returning `false` once it does (because the output
+/// channel is disconnected).
+fn read_file_lines<S, F>(
+    path: PathBuf,
+    errs: &Sender<io::Error>,
+    opts: &LinesOptions<S>,
+    mut emit: F,
+) -> bool
+where
+    F: FnMut(&Arc<PathBuf>, usize, String) -> bool,
+{
+    let file = ch_try!(errs, fs::File::open(&path), return true);
+    let mut buf = io::BufReader::with_capacity(opts.buf_size, file);
+    let path = Arc::new(path);
+    let mut bytes = Vec::new();
+    let mut line_num = 0;
+    loop {
+        bytes.clear();
+        if ch_try!(errs, buf.read_until(b'\n', &mut bytes), return true) == 0 {
+            return true;
+        }
+        line_num += 1;
+        if bytes.last() == Some(&b'\n') {
+            bytes.pop();
+            if bytes.last() == Some(&b'\r') {
+                bytes.pop();
+            }
+        }
+
+        let line = match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(err) => match opts.encoding {
+                EncodingErrors::Error => {
+                    ch!(errs <- io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: {}", path.display(), line_num, err.utf8_error()),
+                    ));
+                    return true;
+                }
+                EncodingErrors::Skip => {
+                    bytes = err.into_bytes();
+                    continue;
+                }
+                EncodingErrors::Lossy => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+            },
+        };
+        if !emit(&path, line_num, line) {
+            return false;
+        }
+        bytes = Vec::new();
+    }
+}
+
+/// Options for [`read_chunks`].
+///
+/// [`read_chunks`]: fn.read_chunks.html
+#[derive(Debug, Clone)]
+pub struct ChunkOptions {
+    threads: usize,
+    chunk_size: u64,
+    delimiter: u8,
+    capacity: usize,
+    scheduler: Scheduler,
+}
+
+impl ChunkOptions {
+    /// Create the default options: read with [`io_threads`] threads, chunks of about 1 MiB
+    /// aligned on `b'\n'`, an output channel holding 16 chunks and the [`Scheduler::Shared`]
+    /// scheduler.
+    ///
+    /// [`io_threads`]: ../fn.io_threads.html
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn new() -> ChunkOptions {
+        ChunkOptions {
+            threads: io_threads(),
+            chunk_size: 1024 * 1024,
+            delimiter: b'\n',
+            capacity: 16,
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    /// Set the number of threads reading chunks. Defaults to [`io_threads`].
+    ///
+    /// [`io_threads`]: ../fn.io_threads.html
+    ///
+    /// # Panics
+    /// Panics if `threads` is zero.
+    pub fn threads(mut self, threads: usize) -> ChunkOptions {
+        assert!(threads > 0, "threads must be non-zero");
+        self.threads = threads;
+        self
+    }
+
+    /// Set the target size (in bytes) of each chunk. Defaults to 1 MiB.
+    ///
+    /// Chunks are extended to the next delimiter, so they will usually be slightly larger than
+    /// this.
+    ///
+    /// # Panics
+    /// Panics if `chunk_size` is zero.
+    pub fn chunk_size(mut self, chunk_size: u64) -> ChunkOptions {
+        assert!(chunk_size > 0, "chunk_size must be non-zero");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Set the byte which chunks are aligned on. Defaults to `b'\n'`.
+    pub fn delimiter(mut self, delimiter: u8) -> ChunkOptions {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Set the number of chunks the output channel can hold. Defaults to 16.
+    ///
+    /// This (multiplied by the `chunk_size`) bounds how much of the file is held in memory.
+    pub fn capacity(mut self, capacity: usize) -> ChunkOptions {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set how the chunks are distributed to the threads. Defaults to [`Scheduler::Shared`].
+    ///
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ChunkOptions {
+        self.scheduler = scheduler;
+        self
+    }
+}
+
+impl Default for ChunkOptions {
+    fn default() -> ChunkOptions {
+        ChunkOptions::new()
+    }
+}
+
+/// Read a (large) file in parallel, sending it in chunks of whole lines.
+///
+/// The file is split into chunks of about `chunk_size` bytes. Each chunk (except the last) ends
+/// directly after a delimiter, so no line is split across two chunks. The chunks are read by
+/// several threads and sent with their offset in the file, so they are _not_ received in order.
+///
+/// Any `io::Error` is sent on `errs` (in the same way as [`ch_try!`]) and the chunk is skipped.
+///
+/// [`ch_try!`]: ../macro.ch_try.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::stages::{read_chunks, ChunkOptions};
+///
+/// # fn main() {
+/// let (send_errs, recv_errs) = ch::unbounded();
+/// let opts = ChunkOptions::new().chunk_size(1024).threads(4);
+/// let recv_chunks = read_chunks("src/lib.rs", send_errs, opts);
+///
+/// let mut chunks: Vec<(u64, Vec<u8>)> = recv_chunks.iter().collect();
+/// assert!(chunks.len() > 1);
+///
+/// // every chunk ends with a complete line
+/// assert!(chunks.iter().all(|&(_, ref c)| c.last() == Some(&b'\n')));
+///
+/// // and the chunks can be put back together
+/// chunks.sort();
+/// let contents: Vec<u8> = chunks.into_iter().flat_map(|(_, c)| c).collect();
+/// assert_eq!(std::fs::read("src/lib.rs").unwrap(), contents);
+/// assert_eq!(0, recv_errs.iter().count());
+/// # }
+/// ```
+pub fn read_chunks<P: AsRef<Path>>(
+    path: P,
+    errs: Sender<io::Error>,
+    opts: ChunkOptions,
+) -> Receiver<(u64, Vec<u8>)> {
+    let (send_chunks, recv_chunks) = ch::bounded(opts.capacity);
+    let path = path.as_ref().to_path_buf();
+    // Open the file here so that an error is only sent once, instead of by every worker.
+    let file = ch_try!(errs, fs::File::open(&path), return recv_chunks);
+    let len = ch_try!(errs, file.metadata(), return recv_chunks).len();
+
+    let num_chunks = len.div_ceil(opts.chunk_size);
+    let (send_index, recv_index) = ch::unbounded();
+    for i in 0..num_chunks {
+        ch!(send_index <- i);
+    }
+    drop(send_index);
+
+    spawn(inherit(move || {
+        // Each worker opens the file once, since the chunks are read by seeking.
+        let pool = Pool::builder()
+            .threads(opts.threads)
+            .scheduler(opts.scheduler)
+            .init(|| None)
+            .build();
+        run_jobs(&pool, recv_index, move |file: &mut Option<fs::File>, i| {
+            let file = match *file {
+                Some(ref mut file) => file,
+                None => file.insert(ch_try!(errs, fs::File::open(&path), return true)),
+            };
+            match ch_try!(errs, read_chunk(file, i, len, &opts), return true) {
+                Some(chunk) => send_chunks.send(chunk).is_ok(),
+                None => true,
+            }
+        });
+        pool.finish();
+    }));
+    recv_chunks
+}
+
+/// Read the chunk at `index`, returning `None` if it is empty.
+///
+/// A chunk is empty when a single line spans the entire chunk, in which case the line is part of
+/// the previous chunk.
+fn read_chunk(
+    file: &mut fs::File,
+    index: u64,
+    len: u64,
+    opts: &ChunkOptions,
+) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let start = chunk_boundary(file, index * opts.chunk_size, len, opts.delimiter)?;
+    let end = chunk_boundary(file, (index + 1) * opts.chunk_size, len, opts.delimiter)?;
+    if start >= end {
+        return Ok(None);
+    }
+    let mut chunk = vec![0; (end - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut chunk)?;
+    Ok(Some((start, chunk)))
+}
+
+/// Find where the chunk starting near `pos` actually starts: directly after the first delimiter
+/// at or after `pos - 1`.
+///
+/// Since this only depends on `pos`, the threads reading neighboring chunks always agree on where
+/// one ends and the next begins.
+fn chunk_boundary(file: &mut fs::File, pos: u64, len: u64, delimiter: u8) -> io::Result<u64> {
+    if pos == 0 || pos >= len {
+        return Ok(pos.min(len));
+    }
+    let mut offset = pos - 1;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = [0; 8 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(len);
+        }
+        if let Some(i) = buf[..read].iter().position(|&b| b == delimiter) {
+            return Ok(offset + i as u64 + 1);
+        }
+        offset += read as u64;
+    }
+}
+
+/// Options for [`retry`] and [`retry_with`].
+///
+/// [`retry`]: fn.retry.html
+/// [`retry_with`]: fn.retry_with.html
+pub struct RetryOptions<E, S = ()> {
+    threads: usize,
+    capacity: usize,
+    max_attempts: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retryable: Option<Retryable<E>>,
+    scheduler: Scheduler,
+    init: Init<S>,
+}
+
+type Retryable<E> = Arc<dyn Fn(&E) -> bool + Send + Sync>;
+
+impl<E> RetryOptions<E> {
+    /// Create the default options: run with [`cpu_threads`] threads, an output channel holding 128
+    /// values and up to 3 attempts per value, backing off from 10ms up to 1s with jitter.
+    /// Every error is retryable and the [`Scheduler::Shared`] scheduler is used.
+    ///
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn new() -> RetryOptions<E> {
+        RetryOptions {
+            threads: cpu_threads(),
+            capacity: 128,
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+            retryable: None,
+            scheduler: Scheduler::default(),
+            init: Init(Arc::new(|| ())),
+        }
+    }
+}
+
+impl<E, S> RetryOptions<E, S> {
+    /// Set the number of threads running the stage. Defaults to [`cpu_threads`].
+    ///
+    /// [`cpu_threads`]: ../fn.cpu_threads.html
+    ///
+    /// # Panics
+    /// Panics if `threads` is zero.
+    pub fn threads(mut self, threads: usize) -> RetryOptions<E, S> {
+        assert!(threads > 0, "threads must be non-zero");
+        self.threads = threads;
+        self
+    }
+
+    /// Set the number of values the output channel can hold. Defaults to 128.
+    pub fn capacity(mut self, capacity: usize) -> RetryOptions<E, S> {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set the maximum number of attempts for each value, including the first. Defaults to 3.
+    ///
+    /// # Panics
+    /// Panics if `max_attempts` is zero.
+    pub fn max_attempts(mut self, max_attempts: usize) -> RetryOptions<E, S> {
+        assert!(max_attempts > 0, "max_attempts must be non-zero");
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set how long to wait before the first retry. Defaults to 10ms.
+    ///
+    /// The wait doubles after every failed attempt, up to the [`max_backoff`].
+    ///
+    /// [`max_backoff`]: #method.max_backoff
+    pub fn backoff(mut self, backoff: Duration) -> RetryOptions<E, S> {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the longest time to wait between attempts. Defaults to 1s.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> RetryOptions<E, S> {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Randomize each wait to between half and all of the backoff, so that values which failed
+    /// together are not all retried at the same time. Defaults to `true`.
+    pub fn jitter(mut self, jitter: bool) -> RetryOptions<E, S> {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only retry the errors for which `retryable` returns `true`. Any other error sends the
+    /// value to the dead letters immediately. Defaults to retrying every error.
+    pub fn retryable<F>(mut self, retryable: F) -> RetryOptions<E, S>
+    where
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Some(Arc::new(retryable));
+        self
+    }
+
+    /// Set how the values are distributed to the threads. Defaults to [`Scheduler::Shared`].
+    ///
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn scheduler(mut self, scheduler: Scheduler) -> RetryOptions<E, S> {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Give each thread its own state, created by calling `init` on the thread, which is passed
+    /// to the function of [`retry_with`].
+    ///
+    /// [`retry_with`]: fn.retry_with.html
+    pub fn init<T, F>(self, init: F) -> RetryOptions<E, T>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        RetryOptions {
+            threads: self.threads,
+            capacity: self.capacity,
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            max_backoff: self.max_backoff,
+            jitter: self.jitter,
+            retryable: self.retryable,
+            scheduler: self.scheduler,
+            init: Init(Arc::new(init)),
+        }
+    }
+
+    /// The time to wait after the failed `attempt` (starting at 1).
+    fn wait(&self, attempt: usize, random: &mut u64) -> Duration {
+        let shift = (attempt - 1).min(31) as u32;
+        let wait = self.backoff
+            .checked_mul(1 << shift)
+            .map_or(self.max_backoff, |w| w.min(self.max_backoff));
+        if !self.jitter {
+            return wait;
+        }
+        // xorshift, which is plenty random enough for spreading out retries.
+        *random ^= *random << 13;
+        *random ^= *random >> 7;
+        *random ^= *random << 17;
+        let half = wait / 2;
+        half + half.mul_f64((*random % 1024) as f64 / 1024.0)
+    }
+}
+
+impl<E> Default for RetryOptions<E> {
+    fn default() -> RetryOptions<E> {
+        RetryOptions::new()
+    }
+}
+
+// Not derived, since `E` and `S` do not need to be `Clone`.
+impl<E, S> Clone for RetryOptions<E, S> {
+    fn clone(&self) -> RetryOptions<E, S> {
+        RetryOptions {
+            threads: self.threads,
+            capacity: self.capacity,
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            max_backoff: self.max_backoff,
+            jitter: self.jitter,
+            retryable: self.retryable.clone(),
+            scheduler: self.scheduler,
+            init: self.init.clone(),
+        }
+    }
+}
+
+impl<E, S> fmt::Debug for RetryOptions<E, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryOptions")
+            .field("threads", &self.threads)
+            .field("capacity", &self.capacity)
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("retryable", &self.retryable.as_ref().map(|_| ".."))
+            .field("scheduler", &self.scheduler)
+            .field("init", &self.init)
+            .finish()
+    }
+}
+
+/// Run `f` on each value received from `recv` in parallel, retrying failures with exponential
+/// backoff.
+///
+/// The `Ok` results are sent on the first channel. A value whose error is not retryable, or which
+/// still fails after the maximum number of attempts, is sent with its last error on the second
+/// channel (the "dead letters") so that it can be inspected or retried later.
+///
+/// The output channel is bounded, while the dead letter channel is not and does not need to be
+/// received from until the stage is done.
+///
+/// The backoff waits use the [`Clock`] of the thread calling `retry`, so tests can skip them
+/// with a [`ManualClock`].
+///
+/// [`Clock`]: ../enum.Clock.html
+/// [`ManualClock`]: ../struct.ManualClock.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::stages::{retry, RetryOptions};
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(16);
+/// spawn(move || {
+///     for v in &["1", "2", "three", "4"] {
+///         ch!(send <- v.to_string());
+///     }
+/// });
+///
+/// let attempts = Arc::new(AtomicUsize::new(0));
+/// let opts = RetryOptions::new()
+///     .max_attempts(5)
+///     .backoff(Duration::from_millis(1))
+///     .retryable(|err: &String| !err.starts_with("invalid"));
+///
+/// let (recv_nums, recv_dead) = {
+///     take!(=attempts);
+///     retry(recv, opts, move |v: &String| {
+///         // "fail" the first few attempts
+///         if attempts.fetch_add(1, AtomicOrdering::SeqCst) < 3 {
+///             return Err("busy".to_string());
+///         }
+///         v.parse::<u32>().map_err(|e| format!("invalid {}: {}", v, e))
+///     })
+/// };
+///
+/// let sum: u32 = recv_nums.iter().sum();
+/// assert_eq!(7, sum);
+///
+/// let dead: Vec<_> = recv_dead.iter().collect();
+/// assert_eq!(1, dead.len());
+/// assert_eq!("three", dead[0].0);
+/// # }
+/// ```
+pub fn retry<T, U, E, F>(
+    recv: Receiver<T>,
+    opts: RetryOptions<E>,
+    f: F,
+) -> (Receiver<U>, Receiver<(T, E)>)
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    E: Send + 'static,
+    F: Fn(&T) -> Result<U, E> + Send + Sync + 'static,
+{
+    retry_with(recv, opts, move |_, value| f(value))
+}
+
+/// Run `f` on each value received from `recv` in parallel, retrying failures like [`retry`],
+/// with the state of the thread running it (see [`RetryOptions::init`]).
+///
+/// [`retry`]: fn.retry.html
+/// [`RetryOptions::init`]: struct.RetryOptions.html#method.init
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::stages::{retry_with, RetryOptions};
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(16);
+/// spawn(move || {
+///     for v in 0..10_u32 {
+///         ch!(send <- v);
+///     }
+/// });
+///
+/// let connects = Arc::new(AtomicUsize::new(0));
+/// let opts = {
+///     take!(=connects);
+///     RetryOptions::new().threads(2).init(move || {
+///         // i.e. each thread opens its own connection once
+///         connects.fetch_add(1, AtomicOrdering::SeqCst);
+///         Vec::new()
+///     })
+/// };
+/// let (recv_out, recv_dead) = retry_with(recv, opts, |sent: &mut Vec<u32>, v: &u32| {
+///     sent.push(*v);
+///     Ok::<_, ()>(v * 2)
+/// });
+///
+/// assert_eq!(90, recv_out.iter().sum::<u32>());
+/// assert_eq!(0, recv_dead.iter().count());
+/// assert!(connects.load(AtomicOrdering::SeqCst) <= 2);
+/// # }
+/// ```
+pub fn retry_with<T, U, E, S, F>(
+    recv: Receiver<T>,
+    opts: RetryOptions<E, S>,
+    f: F,
+) -> (Receiver<U>, Receiver<(T, E)>)
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    E: Send + 'static,
+    S: 'static,
+    F: Fn(&mut S, &T) -> Result<U, E> + Send + Sync + 'static,
+{
+    let (send_out, recv_out) = ch::bounded(opts.capacity);
+    let (send_dead, recv_dead) = ch::unbounded();
+    let clock = Clock::current();
+    spawn(inherit(move || {
+        // Each worker has its own random state for the jitter.
+        let seeds = RandomState::new();
+        let workers = AtomicUsize::new(0);
+        let init = opts.init.clone();
+        let pool = Pool::builder()
+            .threads(opts.threads)
+            .scheduler(opts.scheduler)
+            .init(move || {
+                let mut hasher = seeds.build_hasher();
+                hasher.write_usize(workers.fetch_add(1, AtomicOrdering::SeqCst));
+                (hasher.finish() | 1, (init.0)())
+            })
+            .build();
+        run_jobs(&pool, recv, move |&mut (ref mut random, ref mut state), value| {
+            let mut attempt = 1;
+            let result = loop {
+                match f(state, &value) {
+                    Ok(out) => break Ok(out),
+                    Err(err) => {
+                        let retryable = match opts.retryable {
+                            Some(ref retryable) => retryable(&err),
+                            None => true,
+                        };
+                        if !retryable || attempt >= opts.max_attempts {
+                            break Err(err);
+                        }
+                    }
+                }
+                clock.sleep(opts.wait(attempt, random));
+                attempt += 1;
+            };
+            match result {
+                Ok(out) => send_out.send(out).is_ok(),
+                // The dead letters may be dropped if the caller is not interested in them.
+                Err(err) => {
+                    let _ = send_dead.send((value, err));
+                    true
+                }
+            }
+        });
+        pool.finish();
+    }));
+    (recv_out, recv_dead)
+}
+
+/// Options for [`map_init`].
+///
+/// [`map_init`]: fn.map_init.html
+#[derive(Debug, Clone)]
+pub struct MapOptions {
+    threads: usize,
+    capacity: usize,
+    scheduler: Scheduler,
+}
+
+impl MapOptions {
+    /// Create the default options: run with [`cpu_threads`] threads, an output channel holding
+    /// 128 values and the [`Scheduler::Shared`] scheduler.
+    ///
+    /// [`cpu_threads`]: ../fn.cpu_threads.html
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn new() -> MapOptions {
+        MapOptions {
+            threads: cpu_threads(),
+            capacity: 128,
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    /// Set the number of threads running the stage. Defaults to [`cpu_threads`].
+    ///
+    /// [`cpu_threads`]: ../fn.cpu_threads.html
+    ///
+    /// # Panics
+    /// Panics if `threads` is zero.
+    pub fn threads(mut self, threads: usize) -> MapOptions {
+        assert!(threads > 0, "threads must be non-zero");
+        self.threads = threads;
+        self
+    }
+
+    /// Set the number of values the output channel can hold. Defaults to 128.
+    pub fn capacity(mut self, capacity: usize) -> MapOptions {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set how the values are distributed to the threads. Defaults to [`Scheduler::Shared`].
+    ///
+    /// [`Scheduler::Shared`]: ../enum.Scheduler.html#variant.Shared
+    pub fn scheduler(mut self, scheduler: Scheduler) -> MapOptions {
+        self.scheduler = scheduler;
+        self
+    }
+}
+
+impl Default for MapOptions {
+    fn default() -> MapOptions {
+        MapOptions::new()
+    }
+}
+
+/// Run `f` on each value received from `recv` in parallel, giving each thread its own state
+/// created by `init`.
+///
+/// This is for expensive resources which should only be created once per thread, such as a
+/// compiled regex, a parser or a scratch buffer. It is the same as writing
+/// `let mut state = init();` before the `for v in recv.iter()` loop of each thread.
+///
+/// The values are _not_ sent in order.
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::stages::{map_init, MapOptions};
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(16);
+/// spawn(move || {
+///     for word in "the quick brown fox".split(' ') {
+///         ch!(send <- word);
+///     }
+/// });
+///
+/// let opts = MapOptions::new().threads(2);
+/// let recv_upper = map_init(recv, opts, String::new, |buf: &mut String, word: &str| {
+///     // reuse the thread's buffer to find the length
+///     buf.clear();
+///     buf.extend(word.chars().flat_map(char::to_uppercase));
+///     buf.len()
+/// });
+/// assert_eq!(16, recv_upper.iter().sum::<usize>());
+/// # }
+/// ```
+pub fn map_init<T, U, S, I, F>(recv: Receiver<T>, opts: MapOptions, init: I, f: F) -> Receiver<U>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    S: 'static,
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, T) -> U + Send + Sync + 'static,
+{
+    let (send_out, recv_out) = ch::bounded(opts.capacity);
+    spawn(inherit(move || {
+        let pool = Pool::builder()
+            .threads(opts.threads)
+            .scheduler(opts.scheduler)
+            .init(init)
+            .build();
+        run_jobs(&pool, recv, move |state, v| send_out.send(f(state, v)).is_ok());
+        pool.finish();
+    }));
+    recv_out
+}
+
+/// Submit a job to `pool` running `f` on each value received from `recv`, stopping once `f`
+/// returns `false` because the output was dropped.
+///
+/// Only a couple of jobs per worker are queued at a time, so that a stage still waits for its
+/// output to be received instead of draining its input into the pool.
+pub(crate) fn run_jobs<T, S, F>(pool: &Pool<S>, recv: Receiver<T>, f: F)
+where
+    T: Send + 'static,
+    S: 'static,
+    F: Fn(&mut S, T) -> bool + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let stopped = Arc::new(AtomicBool::new(false));
+    let (send_slot, recv_slot) = ch::bounded(2 * pool.threads());
+    for v in recv.iter() {
+        if stopped.load(AtomicOrdering::SeqCst) || send_slot.send(()).is_err() {
+            return;
+        }
+        take!(=f, =stopped, =recv_slot);
+        pool.submit_with(move |state: &mut S| {
+            let _slot = Slot(recv_slot);
+            if !f(state, v) {
+                stopped.store(true, AtomicOrdering::SeqCst);
+            }
+        });
+    }
+}
+
+/// Frees a slot of `run_jobs` once its job is done, even if the job panicked.
+struct Slot(Receiver<()>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let _ = self.0.try_recv();
+    }
+}
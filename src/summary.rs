@@ -0,0 +1,462 @@
+//! Machine-readable summaries of a pipeline run.
+//!
+//! A [`Summary`] collects statistics while a pipeline runs:
+//!
+//! - [`StageStats`]: the number of items and errors of each stage, and the busy and idle time of
+//!   each of its threads (with [`StageStats::thread`]).
+//! - [`ChannelStats`]: the high-water mark of a channel, sampled by the pipeline itself.
+//!
+//! Once the pipeline's threads have been finished, [`Summary::finish`] returns a [`RunSummary`]
+//! which can be written as JSON, i.e. so that CI jobs can track performance regressions.
+//!
+//! [`Summary`]: struct.Summary.html
+//! [`StageStats`]: struct.StageStats.html
+//! [`StageStats::thread`]: struct.StageStats.html#method.thread
+//! [`ChannelStats`]: struct.ChannelStats.html
+//! [`Summary::finish`]: struct.Summary.html#method.finish
+//! [`RunSummary`]: struct.RunSummary.html
+//!
+//! # Examples
+//! ```rust
+//! #[macro_use] extern crate ergo_sync;
+//! use ergo_sync::*;
+//! use ergo_sync::summary::Summary;
+//!
+//! # fn main() {
+//! let summary = Summary::new();
+//! let parse = summary.stage("parse");
+//! let numbers = summary.channel("numbers");
+//!
+//! let (send, recv) = ch::bounded(16);
+//! let (send_errs, recv_errs) = ch::unbounded();
+//! let producer = spawn(move || {
+//!     for s in &["1", "2", "x", "4"] {
+//!         ch!(send <- s.to_string());
+//!     }
+//! });
+//!
+//! let workers: Vec<_> = (0..2)
+//!     .map(|_| {
+//!         take!(=recv, =send_errs, =parse, =numbers);
+//!         spawn(move || {
+//!             let mut thread = parse.thread();
+//!             let mut sum = 0;
+//!             for s in recv.iter() {
+//!                 numbers.sample(&recv);
+//!                 match thread.busy(|| s.parse::<u64>()) {
+//!                     Ok(n) => {
+//!                         parse.item();
+//!                         sum += n;
+//!                     }
+//!                     Err(err) => ch!(send_errs <- err),
+//!                 }
+//!             }
+//!             sum
+//!         })
+//!     })
+//!     .collect();
+//! drop(send_errs);
+//!
+//! producer.finish();
+//! let sum: u64 = workers.into_iter().map(|w| w.finish()).sum();
+//! parse.errors(recv_errs.iter().count() as u64);
+//! assert_eq!(7, sum);
+//!
+//! let run = summary.finish();
+//! assert_eq!(3, run.stages[0].items);
+//! assert_eq!(1, run.stages[0].errors);
+//! assert_eq!(2, run.stages[0].threads.len());
+//! assert!(run.to_json().starts_with(r#"{"wall_secs":"#));
+//! # }
+//! ```
+
+use std::fmt::{self, Write};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
+
+use std_prelude::*;
+use ch::Receiver;
+use clock::Clock;
+use FinishHandle;
+
+/// Collects the statistics of a pipeline run, see the [module docs](index.html).
+///
+/// The wall time is measured from when the `Summary` is created until it is finished. All of the
+/// times use the [`Clock`] of the thread which created the `Summary`.
+///
+/// [`Clock`]: ../enum.Clock.html
+///
+/// # Examples
+/// ```rust
+/// extern crate ergo_sync;
+/// use ergo_sync::*;
+/// use ergo_sync::summary::{Summary, ThreadSummary};
+///
+/// # fn main() {
+/// let clock = ManualClock::new();
+/// let _guard = Clock::Manual(clock.clone()).enter();
+///
+/// let summary = Summary::new();
+/// let stage = summary.stage("slow");
+/// {
+///     let mut thread = stage.thread();
+///     thread.busy(|| clock.advance(Duration::from_secs(2)));
+///     clock.advance(Duration::from_secs(1));
+/// }
+///
+/// let run = summary.finish();
+/// assert_eq!(Duration::from_secs(3), run.wall_time);
+/// let expected = ThreadSummary {
+///     busy: Duration::from_secs(2),
+///     idle: Duration::from_secs(1),
+/// };
+/// assert_eq!(vec![expected], run.stages[0].threads);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Summary {
+    clock: Clock,
+    started: Instant,
+    stages: Arc<Mutex<Vec<StageStats>>>,
+    channels: Arc<Mutex<Vec<ChannelStats>>>,
+}
+
+impl Summary {
+    /// Start the summary of a run.
+    pub fn new() -> Summary {
+        let clock = Clock::current();
+        Summary {
+            started: clock.now(),
+            clock,
+            stages: Arc::new(Mutex::new(Vec::new())),
+            channels: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Add a stage, returning the stats which its threads should update.
+    pub fn stage<S: Into<String>>(&self, name: S) -> StageStats {
+        let stage = StageStats {
+            inner: Arc::new(StageInner {
+                name: name.into(),
+                clock: self.clock.clone(),
+                items: AtomicU64::new(0),
+                errors: AtomicU64::new(0),
+                threads: Mutex::new(Vec::new()),
+            }),
+        };
+        self.stages
+            .lock()
+            .expect("summary poisoned")
+            .push(stage.clone());
+        stage
+    }
+
+    /// Add a channel, returning the stats which should be sampled as values are received.
+    pub fn channel<S: Into<String>>(&self, name: S) -> ChannelStats {
+        let channel = ChannelStats {
+            inner: Arc::new(ChannelInner {
+                name: name.into(),
+                high_water: AtomicUsize::new(0),
+            }),
+        };
+        self.channels
+            .lock()
+            .expect("summary poisoned")
+            .push(channel.clone());
+        channel
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Summary {
+        Summary::new()
+    }
+}
+
+impl FinishHandle<RunSummary> for Summary {
+    /// Stop the wall clock and return the summary of the run.
+    ///
+    /// This should be called once the threads of the pipeline have been finished, since the
+    /// time of a thread is only recorded once its [`ThreadTimer`] is dropped.
+    ///
+    /// [`ThreadTimer`]: struct.ThreadTimer.html
+    fn finish(self) -> RunSummary {
+        let wall_time = self.clock.now().saturating_duration_since(self.started);
+        let stages = self.stages
+            .lock()
+            .expect("summary poisoned")
+            .iter()
+            .map(|s| StageSummary {
+                name: s.inner.name.clone(),
+                items: s.inner.items.load(AtomicOrdering::SeqCst),
+                errors: s.inner.errors.load(AtomicOrdering::SeqCst),
+                threads: s.inner.threads.lock().expect("summary poisoned").clone(),
+            })
+            .collect();
+        let channels = self.channels
+            .lock()
+            .expect("summary poisoned")
+            .iter()
+            .map(|c| ChannelSummary {
+                name: c.inner.name.clone(),
+                high_water: c.inner.high_water.load(AtomicOrdering::SeqCst),
+            })
+            .collect();
+        RunSummary {
+            wall_time,
+            stages,
+            channels,
+        }
+    }
+}
+
+/// The counts and thread times of a single stage, created with [`Summary::stage`].
+///
+/// [`Summary::stage`]: struct.Summary.html#method.stage
+#[derive(Debug, Clone)]
+pub struct StageStats {
+    inner: Arc<StageInner>,
+}
+
+#[derive(Debug)]
+struct StageInner {
+    name: String,
+    clock: Clock,
+    items: AtomicU64,
+    errors: AtomicU64,
+    threads: Mutex<Vec<ThreadSummary>>,
+}
+
+impl StageStats {
+    /// Count a single item processed by the stage.
+    pub fn item(&self) {
+        self.items(1);
+    }
+
+    /// Count `n` items processed by the stage.
+    pub fn items(&self, n: u64) {
+        self.inner.items.fetch_add(n, AtomicOrdering::Relaxed);
+    }
+
+    /// Count a single error.
+    pub fn error(&self) {
+        self.errors(1);
+    }
+
+    /// Count `n` errors, i.e. the count returned by finishing the thread handling the errors.
+    pub fn errors(&self, n: u64) {
+        self.inner.errors.fetch_add(n, AtomicOrdering::Relaxed);
+    }
+
+    /// Start timing the current thread, which should be called when the thread starts.
+    ///
+    /// The time of the thread is recorded when the returned timer is dropped.
+    pub fn thread(&self) -> ThreadTimer {
+        ThreadTimer {
+            stage: self.clone(),
+            started: self.inner.clock.now(),
+            busy: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Measures how long a thread of a stage is busy, created with [`StageStats::thread`].
+///
+/// The rest of the thread's lifetime (i.e. waiting to receive or send) is counted as idle.
+///
+/// [`StageStats::thread`]: struct.StageStats.html#method.thread
+#[derive(Debug)]
+pub struct ThreadTimer {
+    stage: StageStats,
+    started: Instant,
+    busy: Duration,
+}
+
+impl ThreadTimer {
+    /// Run `f`, counting its time as busy.
+    pub fn busy<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let clock = &self.stage.inner.clock;
+        let start = clock.now();
+        let out = f();
+        self.busy += clock.now().saturating_duration_since(start);
+        out
+    }
+}
+
+impl Drop for ThreadTimer {
+    fn drop(&mut self) {
+        let total = self
+            .stage
+            .inner
+            .clock
+            .now()
+            .saturating_duration_since(self.started);
+        let thread = ThreadSummary {
+            busy: self.busy,
+            idle: total.saturating_sub(self.busy),
+        };
+        // The timer may be dropped while the thread is panicking, so a poisoned lock (which can
+        // only be poisoned by a failed push) is recorded anyway instead of panicking again.
+        self.stage
+            .inner
+            .threads
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(thread);
+    }
+}
+
+/// The high-water mark of a channel, created with [`Summary::channel`].
+///
+/// [`Summary::channel`]: struct.Summary.html#method.channel
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+    inner: Arc<ChannelInner>,
+}
+
+#[derive(Debug)]
+struct ChannelInner {
+    name: String,
+    high_water: AtomicUsize,
+}
+
+impl ChannelStats {
+    /// Record the number of values currently in the channel.
+    ///
+    /// Call this in the loop receiving from the channel. Holding on to the channel here would
+    /// stop it from disconnecting, so it must be sampled by the pipeline itself.
+    pub fn sample<T>(&self, recv: &Receiver<T>) {
+        self.record(recv.len());
+    }
+
+    /// Record the length of the channel, i.e. when sampling a `Sender`.
+    pub fn record(&self, len: usize) {
+        self.inner.high_water.fetch_max(len, AtomicOrdering::Relaxed);
+    }
+}
+
+/// The summary of a pipeline run, returned by finishing a [`Summary`].
+///
+/// [`Summary`]: struct.Summary.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    /// The time from creating the `Summary` until it was finished.
+    pub wall_time: Duration,
+    /// The stages, in the order they were added.
+    pub stages: Vec<StageSummary>,
+    /// The channels, in the order they were added.
+    pub channels: Vec<ChannelSummary>,
+}
+
+/// The summary of a single stage, see [`RunSummary`].
+///
+/// [`RunSummary`]: struct.RunSummary.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageSummary {
+    /// The name of the stage.
+    pub name: String,
+    /// The number of items processed.
+    pub items: u64,
+    /// The number of errors.
+    pub errors: u64,
+    /// The times of each thread, in the order they finished.
+    pub threads: Vec<ThreadSummary>,
+}
+
+/// The busy and idle time of a single thread, see [`RunSummary`].
+///
+/// [`RunSummary`]: struct.RunSummary.html
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThreadSummary {
+    /// The time spent doing work.
+    pub busy: Duration,
+    /// The rest of the thread's lifetime.
+    pub idle: Duration,
+}
+
+/// The high-water mark of a single channel, see [`RunSummary`].
+///
+/// [`RunSummary`]: struct.RunSummary.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSummary {
+    /// The name of the channel.
+    pub name: String,
+    /// The most values the channel was sampled to contain.
+    pub high_water: usize,
+}
+
+impl RunSummary {
+    /// Serialize the summary as (compact) JSON. Durations are in seconds.
+    ///
+    /// ```json
+    /// {"wall_secs":1.5,
+    ///  "stages":[{"name":"parse","items":3,"errors":1,
+    ///             "threads":[{"busy_secs":0.2,"idle_secs":1.3}]}],
+    ///  "channels":[{"name":"numbers","high_water":16}]}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out)
+            .expect("writing to a String never fails");
+        out
+    }
+
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        write!(out, "{{\"wall_secs\":{},\"stages\":[", self.wall_time.as_secs_f64())?;
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            write_json_str(out, &stage.name)?;
+            write!(
+                out,
+                ",\"items\":{},\"errors\":{},\"threads\":[",
+                stage.items, stage.errors
+            )?;
+            for (j, thread) in stage.threads.iter().enumerate() {
+                if j != 0 {
+                    out.push(',');
+                }
+                write!(
+                    out,
+                    "{{\"busy_secs\":{},\"idle_secs\":{}}}",
+                    thread.busy.as_secs_f64(),
+                    thread.idle.as_secs_f64()
+                )?;
+            }
+            out.push_str("]}");
+        }
+        out.push_str("],\"channels\":[");
+        for (i, channel) in self.channels.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            write_json_str(out, &channel.name)?;
+            write!(out, ",\"high_water\":{}}}", channel.high_water)?;
+        }
+        out.push_str("]}");
+        Ok(())
+    }
+}
+
+fn write_json_str(out: &mut String, s: &str) -> fmt::Result {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
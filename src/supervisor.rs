@@ -0,0 +1,611 @@
+//! Restarting worker threads which panic.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use std_prelude::*;
+use ch::{self, Receiver, RecvTimeoutError, Sender};
+use clock::Clock;
+use FinishHandle;
+
+type ChildFn = Arc<dyn Fn(&StopToken) + Send + Sync>;
+
+/// Which children a [`Supervisor`] restarts when one of them panics.
+///
+/// [`Supervisor`]: struct.Supervisor.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Only restart the child which panicked.
+    #[default]
+    OneForOne,
+
+    /// Stop every other child and restart all of them, except for the children which had already
+    /// returned normally.
+    ///
+    /// Use this when the children depend on each other, i.e. when they share channels which are
+    /// recreated by the children themselves.
+    AllForOne,
+}
+
+/// Runs a group of worker threads (the "children"), restarting them when they panic.
+///
+/// Threads created with [`spawn`] die permanently when they panic, silently removing capacity
+/// from a pipeline. A `Supervisor` instead restarts its children according to its [`Strategy`].
+///
+/// A child which returns normally is _not_ restarted. The supervisor finishes once all of its
+/// children have returned.
+///
+/// If more than `max_restarts` restarts happen within the time `window` (measured with the
+/// [`Clock`] of the thread which started the supervisor) the supervisor "escalates": it reports
+/// [`SupervisorEvent::Escalated`], stops all of its children and fails with a
+/// [`SupervisorError`].
+///
+/// Children are stopped cooperatively: each receives a [`StopToken`] which it should check
+/// periodically. A child which is blocked on a channel will also exit once that channel is
+/// disconnected. Children which have not stopped within the [`stop_timeout`] are left running,
+/// so that a child which ignores its `StopToken` cannot hang the supervisor.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`Strategy`]: enum.Strategy.html
+/// [`Clock`]: enum.Clock.html
+/// [`SupervisorError`]: struct.SupervisorError.html
+/// [`SupervisorEvent::Escalated`]: enum.SupervisorEvent.html#variant.Escalated
+/// [`stop_timeout`]: struct.Supervisor.html#method.stop_timeout
+/// [`StopToken`]: struct.StopToken.html
+///
+/// # Examples
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let (send, recv) = ch::bounded(16);
+/// let (send_out, recv_out) = ch::unbounded();
+///
+/// let supervisor = Supervisor::new()
+///     .child("doubler", move |_stop| {
+///         for v in recv.iter() {
+///             if v == 3 {
+///                 panic!("three is not allowed");
+///             }
+///             ch!(send_out <- v * 2);
+///         }
+///     })
+///     .start();
+///
+/// for v in 0..6 {
+///     ch!(send <- v);
+/// }
+/// drop(send);
+///
+/// // the panicking value is lost, but the child keeps going
+/// let events: Vec<_> = supervisor.events().clone().iter().take(1).collect();
+/// supervisor.finish();
+/// let out: Vec<_> = recv_out.iter().collect();
+/// assert_eq!(vec![0, 2, 4, 8, 10], out);
+///
+/// match events[0] {
+///     SupervisorEvent::Restarted { ref child, ref message } => {
+///         assert_eq!("doubler", child);
+///         assert_eq!("three is not allowed", message);
+///     }
+///     _ => panic!("expected a restart"),
+/// }
+/// # }
+/// ```
+///
+/// Escalating once the restart limit is exceeded:
+///
+/// ```rust
+/// #[macro_use] extern crate ergo_sync;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// let supervisor = Supervisor::new()
+///     .max_restarts(2, Duration::from_secs(10))
+///     .child("broken", |_stop| panic!("always broken"))
+///     .child("waiting", |stop| while !stop.wait_timeout(Duration::from_millis(10)) {})
+///     .start();
+///
+/// let events = supervisor.events().clone();
+/// let err = supervisor.join().unwrap_err();
+/// assert_eq!("broken", err.child());
+///
+/// let events: Vec<_> = events.iter().collect();
+/// assert_eq!(3, events.len());
+/// assert_eq!(SupervisorEvent::Escalated(err), events[2]);
+/// # }
+/// ```
+pub struct Supervisor {
+    strategy: Strategy,
+    max_restarts: usize,
+    window: Duration,
+    stop_timeout: Duration,
+    children: Vec<(String, ChildFn)>,
+}
+
+impl Supervisor {
+    /// Create a supervisor with no children, the [`Strategy::OneForOne`] strategy and a limit of
+    /// 3 restarts every 5 seconds.
+    ///
+    /// [`Strategy::OneForOne`]: enum.Strategy.html#variant.OneForOne
+    pub fn new() -> Supervisor {
+        Supervisor {
+            strategy: Strategy::default(),
+            max_restarts: 3,
+            window: Duration::from_secs(5),
+            stop_timeout: Duration::from_secs(5),
+            children: Vec::new(),
+        }
+    }
+
+    /// Set which children are restarted when one panics. Defaults to
+    /// [`Strategy::OneForOne`].
+    ///
+    /// [`Strategy::OneForOne`]: enum.Strategy.html#variant.OneForOne
+    pub fn strategy(mut self, strategy: Strategy) -> Supervisor {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Escalate if there are more than `max_restarts` restarts within `window`. Defaults to 3
+    /// restarts every 5 seconds.
+    ///
+    /// The window is measured with the [`Clock`] of the thread calling [`start`], so it can be
+    /// tested with a [`ManualClock`].
+    ///
+    /// [`Clock`]: enum.Clock.html
+    /// [`start`]: #method.start
+    /// [`ManualClock`]: struct.ManualClock.html
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// let clock = ManualClock::new();
+    /// let _guard = Clock::Manual(clock.clone()).enter();
+    ///
+    /// let (send, recv) = ch::bounded(0);
+    /// let supervisor = Supervisor::new()
+    ///     .max_restarts(1, Duration::from_secs(60))
+    ///     .child("flaky", move |_stop| {
+    ///         for () in recv.iter() {
+    ///             panic!("flaked");
+    ///         }
+    ///     })
+    ///     .start();
+    /// let events = supervisor.events().clone();
+    ///
+    /// // one panic a minute never exceeds the limit
+    /// for _ in 0..3 {
+    ///     ch!(send <- ());
+    ///     ch!(<- events);
+    ///     clock.advance(Duration::from_secs(60));
+    /// }
+    /// drop(send);
+    /// supervisor.finish();
+    /// # }
+    /// ```
+    pub fn max_restarts(mut self, max_restarts: usize, window: Duration) -> Supervisor {
+        self.max_restarts = max_restarts;
+        self.window = window;
+        self
+    }
+
+    /// Set how long the children have to stop whenever the supervisor stops them: when it
+    /// escalates, when it is [stopped] and before restarting them with
+    /// [`Strategy::AllForOne`]. Defaults to 5 seconds.
+    ///
+    /// Children which are still running after the timeout are left running and the supervisor
+    /// carries on anyway. They are detached, like threads created with `spawn`.
+    ///
+    /// [stopped]: struct.SupervisorHandle.html#method.stop
+    /// [`Strategy::AllForOne`]: enum.Strategy.html#variant.AllForOne
+    ///
+    /// # Examples
+    /// ```rust
+    /// #[macro_use] extern crate ergo_sync;
+    /// use ergo_sync::*;
+    ///
+    /// # fn main() {
+    /// let (_send, recv) = ch::unbounded::<()>();
+    /// let supervisor = Supervisor::new()
+    ///     .max_restarts(0, Duration::from_secs(10))
+    ///     .stop_timeout(Duration::from_millis(50))
+    ///     .child("broken", |_stop| panic!("always broken"))
+    ///     // blocked on a channel it does not own, so it never sees the `StopToken`
+    ///     .child("stuck", move |_stop| {
+    ///         let _ = recv.recv();
+    ///     })
+    ///     .start();
+    ///
+    /// let err = supervisor.join().unwrap_err();
+    /// assert_eq!("broken", err.child());
+    /// # }
+    /// ```
+    pub fn stop_timeout(mut self, stop_timeout: Duration) -> Supervisor {
+        self.stop_timeout = stop_timeout;
+        self
+    }
+
+    /// Add a child, which is called again every time it is restarted.
+    ///
+    /// The `name` is used to identify the child in [`SupervisorEvent`]s.
+    ///
+    /// [`SupervisorEvent`]: enum.SupervisorEvent.html
+    pub fn child<N, F>(mut self, name: N, f: F) -> Supervisor
+    where
+        N: Into<String>,
+        F: Fn(&StopToken) + Send + Sync + 'static,
+    {
+        self.children.push((name.into(), Arc::new(f)));
+        self
+    }
+
+    /// Spawn all of the children and the thread supervising them.
+    pub fn start(self) -> SupervisorHandle {
+        let (send_msgs, recv_msgs) = ch::unbounded();
+        let (send_events, recv_events) = ch::unbounded();
+        let mut running = Running {
+            strategy: self.strategy,
+            max_restarts: self.max_restarts,
+            window: self.window,
+            stop_timeout: self.stop_timeout,
+            clock: Clock::current(),
+            children: self.children
+                .into_iter()
+                .map(|(name, f)| Child {
+                    name,
+                    f,
+                    generation: 0,
+                    stop: None,
+                })
+                .collect(),
+            restarts: Vec::new(),
+            stopping: false,
+            send_msgs: send_msgs.clone(),
+            recv_msgs,
+            send_events,
+        };
+        for i in 0..running.children.len() {
+            running.spawn_child(i);
+        }
+        SupervisorHandle {
+            events: recv_events,
+            control: send_msgs,
+            handle: spawn(move || {
+                let _guard = running.clock.clone().enter();
+                running.run()
+            }),
+        }
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Supervisor {
+        Supervisor::new()
+    }
+}
+
+impl fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = self.children.iter().map(|c| &c.0).collect();
+        f.debug_struct("Supervisor")
+            .field("strategy", &self.strategy)
+            .field("max_restarts", &self.max_restarts)
+            .field("window", &self.window)
+            .field("stop_timeout", &self.stop_timeout)
+            .field("children", &names)
+            .finish()
+    }
+}
+
+/// A handle to a running [`Supervisor`].
+///
+/// [`Supervisor`]: struct.Supervisor.html
+#[derive(Debug)]
+pub struct SupervisorHandle {
+    events: Receiver<SupervisorEvent>,
+    control: Sender<Msg>,
+    handle: JoinHandle<Result<(), SupervisorError>>,
+}
+
+impl SupervisorHandle {
+    /// The channel each restart (and escalation) is reported on.
+    ///
+    /// The channel is unbounded, so it does not need to be received from.
+    pub fn events(&self) -> &Receiver<SupervisorEvent> {
+        &self.events
+    }
+
+    /// Stop all of the children. They will not be restarted, even if they panic.
+    pub fn stop(&self) {
+        // The supervisor may have already finished.
+        let _ = self.control.send(Msg::Stop);
+    }
+
+    /// Block until all of the children have returned (or have been stopped), returning the
+    /// error if the supervisor escalated.
+    pub fn join(self) -> Result<(), SupervisorError> {
+        self.handle
+            .join()
+            .expect("supervisor thread is poisoned")
+    }
+}
+
+impl FinishHandle<()> for SupervisorHandle {
+    fn finish(self) {
+        if let Err(err) = self.join() {
+            panic!("finish failed, {}", err);
+        }
+    }
+}
+
+/// An event reported by a [`Supervisor`].
+///
+/// [`Supervisor`]: struct.Supervisor.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// The `child` was restarted because of a panic with `message`.
+    ///
+    /// With [`Strategy::AllForOne`] every restarted child is reported, with the message of the
+    /// child which panicked.
+    ///
+    /// [`Strategy::AllForOne`]: enum.Strategy.html#variant.AllForOne
+    Restarted {
+        /// The name of the restarted child.
+        child: String,
+        /// The panic message which caused the restart.
+        message: String,
+    },
+
+    /// The restart limit was exceeded and all of the children are being stopped.
+    Escalated(SupervisorError),
+}
+
+/// The error returned when a [`Supervisor`] exceeds its restart limit.
+///
+/// [`Supervisor`]: struct.Supervisor.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupervisorError {
+    child: String,
+    message: String,
+}
+
+impl SupervisorError {
+    /// The name of the child whose panic exceeded the limit.
+    pub fn child(&self) -> &str {
+        &self.child
+    }
+
+    /// The message of that panic.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for SupervisorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "supervisor exceeded its restart limit, `{}` panicked: {}",
+            self.child, self.message
+        )
+    }
+}
+
+impl Error for SupervisorError {}
+
+/// Passed to the children of a [`Supervisor`] so that they can be stopped.
+///
+/// [`Supervisor`]: struct.Supervisor.html
+#[derive(Debug, Clone)]
+pub struct StopToken {
+    recv: Receiver<()>,
+    clock: Clock,
+}
+
+impl StopToken {
+    /// Returns `true` if the child should stop.
+    pub fn is_stopped(&self) -> bool {
+        self.recv.is_disconnected()
+    }
+
+    /// Wait for up to `timeout` for the child to be stopped, returning `true` if it should stop.
+    ///
+    /// This can be used instead of `sleep` so that stopping is not delayed. It uses the
+    /// [`Clock`] of the thread which started the supervisor, which is also the clock of the
+    /// children.
+    ///
+    /// [`Clock`]: enum.Clock.html
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        !matches!(
+            self.clock.recv_timeout(&self.recv, timeout),
+            Err(RecvTimeoutError::Timeout)
+        )
+    }
+
+    /// A channel which is disconnected once the child should stop, for use in [`select!`].
+    ///
+    /// Nothing is ever sent on the channel.
+    ///
+    /// [`select!`]: macro.select.html
+    pub fn receiver(&self) -> &Receiver<()> {
+        &self.recv
+    }
+}
+
+enum Msg {
+    Exited(usize, usize, thread::Result<()>),
+    Stop,
+}
+
+struct Child {
+    name: String,
+    f: ChildFn,
+    generation: usize,
+    /// Dropped to stop the child, `None` once the child is not running.
+    stop: Option<Sender<()>>,
+}
+
+/// The state of the supervising thread.
+struct Running {
+    strategy: Strategy,
+    max_restarts: usize,
+    window: Duration,
+    stop_timeout: Duration,
+    /// The clock of the thread which started the supervisor, used by it and its children.
+    clock: Clock,
+    children: Vec<Child>,
+    restarts: Vec<Instant>,
+    stopping: bool,
+    send_msgs: Sender<Msg>,
+    recv_msgs: Receiver<Msg>,
+    send_events: Sender<SupervisorEvent>,
+}
+
+impl Running {
+    fn run(mut self) -> Result<(), SupervisorError> {
+        while self.children.iter().any(|c| c.stop.is_some()) {
+            let Some((i, result)) = self.next_exit() else {
+                continue;
+            };
+            let Err(payload) = result else {
+                continue;
+            };
+            if self.stopping {
+                continue;
+            }
+
+            let message = panic_message(&*payload);
+            let now = self.clock.now();
+            let window = self.window;
+            self.restarts.retain(|&t| now.duration_since(t) < window);
+            self.restarts.push(now);
+            if self.restarts.len() > self.max_restarts {
+                let err = SupervisorError {
+                    child: self.children[i].name.clone(),
+                    message,
+                };
+                self.send_event(SupervisorEvent::Escalated(err.clone()));
+                self.stopping = true;
+                self.stop_all();
+                return Err(err);
+            }
+
+            let restart: Vec<usize> = match self.strategy {
+                Strategy::OneForOne => vec![i],
+                Strategy::AllForOne => {
+                    let stopped = self.stop_all();
+                    if self.stopping {
+                        // Stopped while waiting for the other children.
+                        return Ok(());
+                    }
+                    // The children which already returned normally are done.
+                    (0..self.children.len())
+                        .filter(|&j| j == i || stopped[j])
+                        .collect()
+                }
+            };
+            for j in restart {
+                self.spawn_child(j);
+                self.send_event(SupervisorEvent::Restarted {
+                    child: self.children[j].name.clone(),
+                    message: message.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the next child to exit, handling any `Stop` message.
+    fn next_exit(&mut self) -> Option<(usize, thread::Result<()>)> {
+        match self.recv_msgs.recv().expect("the supervisor holds a sender") {
+            Msg::Exited(i, generation, result) => {
+                if generation != self.children[i].generation {
+                    return None;
+                }
+                self.children[i].stop = None;
+                Some((i, result))
+            }
+            Msg::Stop => {
+                self.stopping = true;
+                self.stop_all();
+                None
+            }
+        }
+    }
+
+    /// Stop every child and wait for them to exit, giving up after the `stop_timeout`.
+    ///
+    /// Returns which of the children were running, and so were stopped.
+    fn stop_all(&mut self) -> Vec<bool> {
+        let stopped: Vec<bool> = self.children.iter().map(|c| c.stop.is_some()).collect();
+        let mut running = stopped.clone();
+        for child in &mut self.children {
+            child.stop = None;
+        }
+        let deadline = self.clock.now() + self.stop_timeout;
+        while running.iter().any(|&r| r) {
+            let remaining = deadline.saturating_duration_since(self.clock.now());
+            let msg = match self.clock.recv_timeout(&self.recv_msgs, remaining) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    unreachable!("the supervisor holds a sender")
+                }
+            };
+            match msg {
+                Msg::Exited(i, generation, _) => {
+                    if generation == self.children[i].generation {
+                        running[i] = false;
+                    }
+                }
+                Msg::Stop => self.stopping = true,
+            }
+        }
+        stopped
+    }
+
+    fn spawn_child(&mut self, i: usize) {
+        let (send_stop, recv_stop) = ch::bounded(1);
+        let child = &mut self.children[i];
+        child.generation += 1;
+        child.stop = Some(send_stop);
+        let token = StopToken {
+            recv: recv_stop,
+            clock: self.clock.clone(),
+        };
+        let generation = child.generation;
+        let f = child.f.clone();
+        let send_msgs = self.send_msgs.clone();
+        let clock = self.clock.clone();
+        spawn(move || {
+            let _guard = clock.enter();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(&token)));
+            let _ = send_msgs.send(Msg::Exited(i, generation, result));
+        });
+    }
+
+    fn send_event(&self, event: SupervisorEvent) {
+        // The events may not be received at all.
+        let _ = self.send_events.send(event);
+    }
+}
+
+/// Get the message of a panic, as printed by the default panic hook.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
@@ -0,0 +1,185 @@
+//! Choosing the number of threads for CPU and IO work.
+
+use std::env;
+#[cfg(target_os = "linux")]
+use std::fs;
+
+use num_cpus;
+#[cfg(target_os = "linux")]
+use affinity::allowed_cores;
+
+/// The environment variable overriding `cpu_threads`.
+const CPU_THREADS_VAR: &str = "ERGO_SYNC_CPU_THREADS";
+
+/// The environment variable overriding `io_threads`.
+const IO_THREADS_VAR: &str = "ERGO_SYNC_IO_THREADS";
+
+/// The number of IO threads when it is not overridden.
+const IO_THREADS: usize = 8;
+
+/// The number of threads to use for "CPU work", which is the default for [`Pool`]s and CPU bound
+/// stages.
+///
+/// This is `num_cpus::get()` limited to the CPUs the process can actually use. On Linux that is
+/// the cores in the process's affinity mask (i.e. when started with `taskset`) and the CPU quota
+/// of its cgroup (v1 or v2), so that a container limited to 2 CPUs on a 64 core host gets 2
+/// threads instead of 64.
+///
+/// It can be overridden by setting the `ERGO_SYNC_CPU_THREADS` environment variable, so the
+/// threads can be tuned when deploying without a rebuild.
+///
+/// [`Pool`]: struct.Pool.html
+///
+/// # Panics
+/// Panics if `ERGO_SYNC_CPU_THREADS` is set but is not a positive integer.
+///
+/// # Examples
+/// ```rust
+/// # extern crate ergo_sync;
+/// use std::env;
+/// use ergo_sync::*;
+///
+/// # fn main() {
+/// assert!(cpu_threads() >= 1);
+/// assert!(cpu_threads() <= num_cpus::get());
+///
+/// env::set_var("ERGO_SYNC_CPU_THREADS", "3");
+/// assert_eq!(3, cpu_threads());
+/// assert_eq!(3, Pool::builder().build().threads());
+/// # }
+/// ```
+pub fn cpu_threads() -> usize {
+    if let Some(threads) = from_env(CPU_THREADS_VAR) {
+        return threads;
+    }
+    #[allow(unused_mut)]
+    let mut threads = num_cpus::get();
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(cores) = allowed_cores() {
+            if !cores.is_empty() {
+                threads = threads.min(cores.len());
+            }
+        }
+        if let Some(quota) = cgroup::cpu_quota() {
+            threads = threads.min(quota);
+        }
+    }
+    threads.max(1)
+}
+
+/// The number of threads to use for "IO work", which is the default for stages which read files.
+///
+/// This is 8, since most storage devices only provide between 4 and 16 channels. It can be
+/// overridden by setting the `ERGO_SYNC_IO_THREADS` environment variable, i.e. to use more
+/// threads when reading from network storage.
+///
+/// # Panics
+/// Panics if `ERGO_SYNC_IO_THREADS` is set but is not a positive integer.
+pub fn io_threads() -> usize {
+    from_env(IO_THREADS_VAR).unwrap_or(IO_THREADS)
+}
+
+/// Parse the number of threads from the environment variable `var`, if it is set.
+fn from_env(var: &str) -> Option<usize> {
+    let value = env::var_os(var)?;
+    match value.to_str().and_then(|v| v.trim().parse().ok()) {
+        Some(threads) if threads > 0 => Some(threads),
+        _ => panic!("{} must be a positive integer, got {:?}", var, value),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    //! Reading the CPU quota of the current process's cgroup.
+
+    use super::fs;
+    use std_prelude::*;
+
+    const ROOT: &str = "/sys/fs/cgroup";
+
+    /// The CPU quota rounded up to whole CPUs, or `None` if there is no quota.
+    pub fn cpu_quota() -> Option<usize> {
+        let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let mut quota: Option<usize> = None;
+        for line in cgroups.lines() {
+            // i.e. `0::/user.slice` (v2) or `4:cpu,cpuacct:/docker/abc` (v1)
+            let mut fields = line.splitn(3, ':');
+            let (Some(_), Some(controllers), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let found = if controllers.is_empty() {
+                v2_quota(path)
+            } else if controllers.split(',').any(|c| c == "cpu") {
+                v1_quota(controllers, path)
+            } else {
+                continue;
+            };
+            if let Some(found) = found {
+                quota = Some(quota.map_or(found, |q| q.min(found)));
+            }
+        }
+        quota
+    }
+
+    /// The smallest `cpu.max` of the cgroup and its ancestors.
+    fn v2_quota(path: &str) -> Option<usize> {
+        let mut dir = PathBuf::from(ROOT);
+        dir.push(path.trim_start_matches('/'));
+        let mut quota: Option<usize> = None;
+        loop {
+            if let Ok(max) = fs::read_to_string(dir.join("cpu.max")) {
+                // i.e. `max 100000` (no quota) or `200000 100000` (2 CPUs)
+                let mut fields = max.split_whitespace();
+                if let (Some(limit), Some(period)) = (fields.next(), fields.next()) {
+                    if let (Ok(limit), Ok(period)) = (limit.parse(), period.parse()) {
+                        let found = cpus(limit, period);
+                        quota = Some(quota.map_or(found, |q| q.min(found)));
+                    }
+                }
+            }
+            if dir == Path::new(ROOT) || !dir.pop() {
+                return quota;
+            }
+        }
+    }
+
+    /// The quota from `cpu.cfs_quota_us` and `cpu.cfs_period_us`.
+    fn v1_quota(controllers: &str, path: &str) -> Option<usize> {
+        let mount = Path::new(ROOT).join(controllers);
+        let mount = if mount.exists() {
+            mount
+        } else {
+            Path::new(ROOT).join("cpu")
+        };
+        // Inside a container the cgroup is usually mounted at the root of the controller, even
+        // though the path is that of the host.
+        let candidates = [mount.join(path.trim_start_matches('/')), mount];
+        for dir in &candidates {
+            let read = |name| {
+                fs::read_to_string(dir.join(name))
+                    .ok()
+                    .and_then(|s| s.trim().parse::<i64>().ok())
+            };
+            let (limit, period) = (read("cpu.cfs_quota_us"), read("cpu.cfs_period_us"));
+            if let (Some(limit), Some(period)) = (limit, period) {
+                if limit <= 0 || period <= 0 {
+                    // -1 means there is no quota.
+                    return None;
+                }
+                return Some(cpus(limit as u64, period as u64));
+            }
+        }
+        None
+    }
+
+    /// The number of CPUs a quota of `limit` per `period` amounts to, rounded up.
+    fn cpus(limit: u64, period: u64) -> usize {
+        if period == 0 {
+            return usize::MAX;
+        }
+        (limit.div_ceil(period) as usize).max(1)
+    }
+}